@@ -1,6 +1,6 @@
 extern crate partition_index;
 
-use partition_index::filter::cuckoo::CuckooFilter;
+use partition_index::filter::cuckoo::{BucketCount, BucketReduction, CuckooFilter, HashScheme};
 use partition_index::filter::Filter;
 
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
@@ -13,6 +13,20 @@ fn insert_n(n: u64, slots: u64, entries_per_slot: u64) -> CuckooFilter {
     filter
 }
 
+fn insert_n_with(n: u64, buckets: BucketCount, entries_per_slot: u64) -> CuckooFilter {
+    let mut filter = CuckooFilter::with_scheme(buckets, entries_per_slot, HashScheme::default());
+    (0..n).for_each(|key| {
+        filter.insert(key);
+    });
+    filter
+}
+
+const REDUCTIONS: [(&str, BucketReduction); 3] = [
+    ("modulo", BucketReduction::Modulo),
+    ("power_of_two", BucketReduction::PowerOfTwo),
+    ("fast_range", BucketReduction::FastRange),
+];
+
 fn contains(f: &dyn Filter) -> bool {
     f.contains(0)
 }
@@ -57,12 +71,43 @@ fn contains_bench_vary_n(c: &mut Criterion) {
     }
 }
 
+// 2^15 buckets with 4 entries each, filled to 75%, so all reductions apply
+fn insert_bench_vary_reduction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cuckoo::insert_varying_reduction");
+    for (name, reduction) in REDUCTIONS {
+        let buckets = BucketCount::new(1 << 15, reduction);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(name),
+            &buckets,
+            |b, &buckets| b.iter(|| insert_n_with(3 << 15, buckets, 4)),
+        );
+    }
+}
+
+fn contains_bench_vary_reduction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cuckoo::contains_varying_reduction");
+    for (name, reduction) in REDUCTIONS {
+        // precompute filter outside of the contains benchmark
+        let filter = insert_n_with(3 << 15, BucketCount::new(1 << 15, reduction), 4);
+        let mut key = 0u64;
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                // vary the key, so the buckets aren't always in the cache
+                key = key.wrapping_add(1);
+                black_box(&filter).contains(key)
+            })
+        });
+    }
+}
+
 criterion_group!(
     benches,
     insert_bench_vary_d,
     insert_bench_vary_n,
     contains_bench_vary_d,
-    contains_bench_vary_n
+    contains_bench_vary_n,
+    insert_bench_vary_reduction,
+    contains_bench_vary_reduction
 );
 
 criterion_main!(benches);
@@ -2,7 +2,10 @@ use std::time::{Duration, SystemTime};
 
 use rstats::{MStats, Medianf64, Stats};
 
-use crate::index::{poc::PersistentIndex, PartitionFilter, PartitionIndex};
+use crate::{
+    filter::cuckoo::fingerprint::Fingerprint,
+    index::{poc::PersistentIndex, PartitionFilter, PartitionIndex},
+};
 
 // Simple partition that has a start value and a size.
 // It covers the values in range [start, start + length).
@@ -24,6 +27,7 @@ pub struct BenchmarkResult {
     pub partition_size: u64,
     pub num_buckets: u64,
     pub bucket_size: u64,
+    pub fingerprint_bits: u8,
     pub parallelism: usize,
     pub qps: u128,
     pub ameanstats: MStats,
@@ -35,7 +39,7 @@ pub struct BenchmarkResult {
 }
 
 pub fn result_csv_header() -> String {
-    "queries,partitions,elements per partition,buckets,bucket size,parallelism,\
+    "queries,partitions,elements per partition,buckets,bucket size,fingerprint bits,parallelism,\
     queries per second,mean latency (μs),std dev latency,\
     median (μs),mad,\
     read throughput (MB/s),false positive rate,expected fp rate,occupancy"
@@ -47,12 +51,13 @@ pub fn result_csv_line(benchmark_result: &BenchmarkResult) -> String {
     // queries per second,mean latency (μs),std dev latency,
     // median (μs),mad,read throughput (MB/s)
     format!(
-        "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+        "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
         benchmark_result.num_queries,
         benchmark_result.partitions,
         benchmark_result.partition_size,
         benchmark_result.num_buckets,
         benchmark_result.bucket_size,
+        benchmark_result.fingerprint_bits,
         benchmark_result.parallelism,
         benchmark_result.qps,
         benchmark_result.ameanstats.centre,
@@ -70,20 +75,21 @@ fn index_partitions(
     index: &mut impl PartitionIndex<BenchmarkPartition>,
     partitions: &[BenchmarkPartition],
 ) -> anyhow::Result<()> {
-    let partitions: Vec<_> = partitions.into_iter().map(|bp| {
-        (bp.clone(), bp.start..(bp.start + bp.length))
-    }).collect();
+    let partitions: Vec<_> = partitions
+        .iter()
+        .map(|bp| (bp.clone(), bp.start..(bp.start + bp.length)))
+        .collect();
     index.add_many(partitions)
 }
 
-pub fn create_index(
+/// Create a benchmark index in `index_root`, storing fingerprints of type `F`.
+pub fn create_index<F: Fingerprint>(
     index_root: &str,
     num_partitions: u64,
     partition_size: u64,
     buckets: u64,
 ) -> anyhow::Result<()> {
-    let mut partitions = vec![];
-    partitions.reserve(num_partitions as usize);
+    let mut partitions = Vec::with_capacity(num_partitions as usize);
 
     // Note that we don't store actual values, we only store what's effectively a range
     // that allows us to generate all the values. This enables us to index data much larger
@@ -95,7 +101,8 @@ pub fn create_index(
         });
     }
 
-    let mut index = PersistentIndex::try_new(buckets, index_root.to_string())?;
+    let mut index: PersistentIndex<_, F> =
+        PersistentIndex::try_new(buckets, index_root.to_string())?;
     for p in partitions.chunks(1024) {
         index_partitions(&mut index, p)?;
         let size = index.estimate_mem_size();
@@ -120,12 +127,18 @@ struct QueryRun {
     false_positives: usize,
 }
 
-fn run_query(index: &PersistentIndex<BenchmarkPartition>, i: u64, max_elem: u64) -> anyhow::Result<QueryRun> {
+fn run_query<F: Fingerprint>(
+    index: &PersistentIndex<BenchmarkPartition, F>,
+    i: u64,
+    max_elem: u64,
+) -> anyhow::Result<QueryRun> {
     let s = SystemTime::now();
-    let results = index.query(i)?.len();
+    let results = index.query_ids(i)?.len();
     Ok(QueryRun {
         duration: s.elapsed()?,
-        false_positives: if i >= max_elem { 
+        // queries yield each partition at most once, so every result except for
+        // the partition actually containing `i` is a false positive
+        false_positives: if i >= max_elem {
             results
         } else {
             assert!(results > 0);
@@ -134,8 +147,8 @@ fn run_query(index: &PersistentIndex<BenchmarkPartition>, i: u64, max_elem: u64)
     })
 }
 
-pub fn run_benchmark(
-    index: &PersistentIndex<BenchmarkPartition>,
+pub fn run_benchmark<F: Fingerprint>(
+    index: &PersistentIndex<BenchmarkPartition, F>,
     duration: Duration,
     parallelism: usize,
 ) -> anyhow::Result<BenchmarkResult> {
@@ -154,10 +167,9 @@ pub fn run_benchmark(
             let end = start + batch_size;
             s.spawn(move |_| {
                 *res_ref = (start..end)
-                    .into_iter()
                     .map(|i| {
                         if start_querying.elapsed().unwrap() < duration {
-                            Some(run_query(&index, i as u64, index_size).unwrap())
+                            Some(run_query(index, i as u64, index_size).unwrap())
                         } else {
                             None
                         }
@@ -190,8 +202,11 @@ pub fn run_benchmark(
     let ameanstats = durations.ameanstd()?;
     let med = durations.medstats()?;
     let index_capacity = index.num_slots() as u64 * index.num_buckets();
-    let false_positive_rate = false_positives as f64 / (num_queries * index.num_partitions()) as f64;
-    let expected_fp_rate = (2 * index.num_slots()) as f64 / (65535 * index.num_partitions()) as f64;
+    let false_positive_rate =
+        false_positives as f64 / (num_queries * index.num_partitions()) as f64;
+    // each of the slots in both buckets matches with a probability of 1 / (2^bits - 1)
+    let expected_fp_rate =
+        (2 * index.num_slots()) as f64 / (F::mask() as f64 * index.num_partitions() as f64);
     let occupancy = index.elements() as f64 / index_capacity as f64;
     Ok(BenchmarkResult {
         num_queries,
@@ -199,11 +214,16 @@ pub fn run_benchmark(
         partition_size,
         num_buckets: index.num_buckets(),
         bucket_size: index.num_slots() as u64,
+        fingerprint_bits: F::BITS,
         parallelism,
         qps: num_queries as u128 * 1000 / query_duration.as_millis(),
         ameanstats,
         medianstats: med,
-        read_throughput: read_throughput(&query_duration, index.num_slots(), num_queries),
+        read_throughput: read_throughput(
+            &query_duration,
+            index.num_slots() * std::mem::size_of::<F>(),
+            num_queries,
+        ),
         false_positive_rate,
         expected_fp_rate,
         occupancy,
@@ -211,8 +231,8 @@ pub fn run_benchmark(
 }
 
 /// compute the MB/s read performance
-pub fn read_throughput(d: &Duration, num_slots: usize, num_queries: usize) -> f64 {
-    // read b buckets times two bytes times two buckets times num queries
+pub fn read_throughput(d: &Duration, bucket_bytes: usize, num_queries: usize) -> f64 {
+    // read bucket bytes times two buckets times num queries
     // 1000 -> because we do millis
-    ((num_slots * num_queries * 2 * 2 * 1000) / (1 << 20)) as f64 / d.as_millis() as f64
+    ((bucket_bytes * num_queries * 2 * 1000) / (1 << 20)) as f64 / d.as_millis() as f64
 }
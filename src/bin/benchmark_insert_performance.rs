@@ -1,4 +1,4 @@
-use partition_index::{self, benchmarks::create_index};
+use partition_index::{self, benchmarks::create_index, filter::cuckoo::fingerprint::U12};
 use std::time::SystemTime;
 
 fn main() -> anyhow::Result<()> {
@@ -8,8 +8,19 @@ fn main() -> anyhow::Result<()> {
     let num_partitions: u64 = args[2].parse()?;
     let partition_size: u64 = args[3].parse()?;
     let buckets: u64 = args[4].parse()?;
+    // optional: the fingerprint width in bits, 16 by default
+    let fingerprint_bits: u8 = args.get(5).map_or(Ok(16), |bits| bits.parse())?;
     let start_indexing = SystemTime::now();
-    create_index(file_path, num_partitions, partition_size, buckets)?;
+    match fingerprint_bits {
+        8 => create_index::<u8>(file_path, num_partitions, partition_size, buckets)?,
+        12 => create_index::<U12>(file_path, num_partitions, partition_size, buckets)?,
+        16 => create_index::<u16>(file_path, num_partitions, partition_size, buckets)?,
+        32 => create_index::<u32>(file_path, num_partitions, partition_size, buckets)?,
+        other => anyhow::bail!(
+            "unsupported fingerprint width {}, use 8, 12, 16 or 32",
+            other
+        ),
+    }
     let insert_duration = start_indexing.elapsed()?;
     let index_size = num_partitions * partition_size;
     eprintln!(
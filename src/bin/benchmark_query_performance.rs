@@ -2,18 +2,50 @@ use std::time::Duration;
 
 use partition_index::{
     self,
-    benchmarks::{result_csv_line, run_benchmark, BenchmarkPartition},
-    index::poc::PersistentIndex,
+    benchmarks::{result_csv_line, run_benchmark, BenchmarkPartition, BenchmarkResult},
+    filter::cuckoo::fingerprint::{Fingerprint, U12},
+    index::poc::{self, LoadOptions, PersistentIndex, ReadMode},
 };
 
+fn load_and_run<F: Fingerprint>(
+    index_root: &str,
+    read_mode: ReadMode,
+    time_limit: Duration,
+    parallelism: usize,
+) -> anyhow::Result<BenchmarkResult> {
+    let index = PersistentIndex::<BenchmarkPartition, F>::try_load_from_disk_with(
+        index_root.to_string(),
+        LoadOptions {
+            read_mode,
+            // reading all segments upfront would warm the page cache
+            verify_on_load: false,
+            verify_on_read: false,
+            recover: false,
+        },
+    )?;
+    run_benchmark(&index, time_limit, parallelism)
+}
+
 fn main() -> anyhow::Result<()> {
     use std::env;
     let args: Vec<String> = env::args().collect();
     let index_root = &args[1];
-    let time_limit = args[2].parse()?;
+    let time_limit = Duration::from_secs(args[2].parse()?);
     let parallelism = args[3].parse()?;
-    let index = PersistentIndex::<BenchmarkPartition>::try_load_from_disk(index_root.clone())?;
-    let benchmark_result = run_benchmark(&index, Duration::from_secs(time_limit), parallelism)?;
+    // optional: serve queries from memory-mapped segments instead of reading them
+    let read_mode = match args.get(4).map(String::as_str) {
+        None | Some("buffered") => ReadMode::Buffered,
+        Some("mmap") => ReadMode::Mmap,
+        Some(other) => anyhow::bail!("unknown read mode '{}', use 'buffered' or 'mmap'", other),
+    };
+    // the index is loaded with the fingerprint width it was created with
+    let benchmark_result = match poc::read_format(index_root)?.fingerprint_bits {
+        8 => load_and_run::<u8>(index_root, read_mode, time_limit, parallelism)?,
+        12 => load_and_run::<U12>(index_root, read_mode, time_limit, parallelism)?,
+        16 => load_and_run::<u16>(index_root, read_mode, time_limit, parallelism)?,
+        32 => load_and_run::<u32>(index_root, read_mode, time_limit, parallelism)?,
+        bits => unreachable!("reading the header rejects {} bit fingerprints", bits),
+    };
     eprintln!("{}", result_csv_line(&benchmark_result));
     println!("Median     {}", benchmark_result.medianstats);
     println!("Arithmetic {}", benchmark_result.ameanstats);
@@ -1,4 +1,4 @@
-use std::{fs, io::Read, os::unix::prelude::MetadataExt, path::PathBuf, str::FromStr};
+use partition_index::{benchmarks::BenchmarkPartition, index::poc::PersistentIndex};
 
 // We're trying to find out why our Cuckoo Index has a false positive
 // rate that is 10x above the expected value:
@@ -18,23 +18,13 @@ use std::{fs, io::Read, os::unix::prelude::MetadataExt, path::PathBuf, str::From
 //     in a bucket is far higher than it should be
 //   - in this case, there should be ~ 11 mio values in a bucket,
 //     so the average count per fingerprint should roughly be 150
-fn fingerprint_distribution(index_root: &PathBuf) -> anyhow::Result<()> {
+// - indexes now record their hash scheme: `Split128` derives fingerprints and
+//   buckets from independent halves of one hash, ruling out correlation between them
+fn fingerprint_distribution(index: &PersistentIndex<BenchmarkPartition>) -> anyhow::Result<()> {
     let mut fingerprints = [0u32; 1 << 16];
-    for bucket_file in index_root.read_dir()? {
-        let bucket_file = bucket_file?;
-        let mut f = fs::OpenOptions::new()
-            .read(true)
-            .write(false)
-            .open(bucket_file.path())?;
-        let mut buf: Vec<u8> = vec![];
-        buf.reserve_exact(bucket_file.metadata()?.size() as usize / 2);
-        f.read_to_end(&mut buf)?;
-        let num_elems = buf.len() / 2;
-        let as_u16 = unsafe { std::slice::from_raw_parts(buf.as_ptr().cast::<u16>(), num_elems) };
-        for fp in as_u16 {
-            fingerprints[*fp as usize] += 1;
-        }
-        break;
+    // a single bucket is enough to see the skew
+    for fp in index.read_bucket(0)? {
+        fingerprints[fp as usize] += 1;
     }
     eprintln!(
         "tp;fingerprint distribution:\n{:?}\nrange: [{}, {}]",
@@ -56,7 +46,8 @@ fn fingerprint_distribution(index_root: &PathBuf) -> anyhow::Result<()> {
 fn main() -> anyhow::Result<()> {
     use std::env;
     let args: Vec<String> = env::args().collect();
-    let index_root = PathBuf::from_str(&args[1])?.join("index");
-    fingerprint_distribution(&index_root)?;
+    let index = PersistentIndex::<BenchmarkPartition>::try_load_from_disk(args[1].clone())?;
+    eprintln!("tp;hash scheme: {:?}", index.scheme());
+    fingerprint_distribution(&index)?;
     Ok(())
 }
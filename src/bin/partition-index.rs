@@ -0,0 +1,270 @@
+use std::{fs, path::Path, time::SystemTime};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+use partition_index::{
+    index::{key::ValueType, poc::PersistentIndex},
+    parquet::{
+        find_parquet_files, index_parquet_file, query_parquet, update_parquet, Granularity,
+        IndexedColumns, ParquetPartition,
+    },
+};
+
+fn main() -> anyhow::Result<()> {
+    let matches = Command::new("partition-index")
+        .about("Index Parquet files to find the partitions that may contain a value")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("build")
+                .about("Build a new index over the Parquet files in a directory")
+                .arg(
+                    Arg::new("root")
+                        .required(true)
+                        .help("Directory of the new index"),
+                )
+                .arg(
+                    Arg::new("data")
+                        .required(true)
+                        .help("Parquet file or directory of Parquet files to index"),
+                )
+                .arg(
+                    Arg::new("column")
+                        .long("column")
+                        .short('c')
+                        .required(true)
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .help("Column to index, can be given multiple times"),
+                )
+                .arg(
+                    Arg::new("granularity")
+                        .long("granularity")
+                        .takes_value(true)
+                        .value_parser(["file", "row-group"])
+                        .default_value("file")
+                        .help("Index each file or each row group as a partition"),
+                )
+                .arg(
+                    Arg::new("buckets")
+                        .long("buckets")
+                        .takes_value(true)
+                        .value_parser(value_parser!(u64))
+                        .default_value("4096"),
+                )
+                .arg(
+                    Arg::new("max-memory")
+                        .long("max-memory")
+                        .takes_value(true)
+                        .value_parser(value_parser!(usize))
+                        .default_value("1073741824")
+                        .help("Persist whenever the in-memory index exceeds this many bytes"),
+                ),
+        )
+        .subcommand(
+            Command::new("update")
+                .about("Index new and changed Parquet files, and remove deleted ones")
+                .after_help(
+                    "Files are identified by their path, so DATA must be given the same way as \
+                    when building the index. Indexes the same columns with the same granularity \
+                    as before.",
+                )
+                .arg(
+                    Arg::new("root")
+                        .required(true)
+                        .help("Directory of the index"),
+                )
+                .arg(
+                    Arg::new("data")
+                        .required(true)
+                        .help("Parquet file or directory of Parquet files to index"),
+                )
+                .arg(
+                    Arg::new("compact")
+                        .long("compact")
+                        .help("Compact the index to reclaim the space of removed partitions"),
+                ),
+        )
+        .subcommand(
+            Command::new("query")
+                .about("Print the partitions that may contain a value")
+                .after_help(
+                    "Partitions are pruned by the min/max statistics of the column first, \
+                    then by the index.",
+                )
+                .arg(
+                    Arg::new("root")
+                        .required(true)
+                        .help("Directory of the index"),
+                )
+                .arg(
+                    Arg::new("column")
+                        .long("column")
+                        .short('c')
+                        .required(true)
+                        .takes_value(true)
+                        .help("Indexed column the value belongs to"),
+                )
+                .arg(
+                    Arg::new("value")
+                        .long("value")
+                        .short('v')
+                        .required(true)
+                        .takes_value(true)
+                        .allow_hyphen_values(true),
+                )
+                .arg(
+                    Arg::new("type")
+                        .long("type")
+                        .short('t')
+                        .takes_value(true)
+                        .value_parser(|s: &str| s.parse::<ValueType>().map_err(|e| e.to_string()))
+                        .help(
+                            "Type of the value: int (i8 ... u64), float (f32, f64), string, \
+                            binary (hex digits), bool, date (YYYY-MM-DD) or decimal. \
+                            Defaults to the type of the column when the index was built",
+                        ),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .takes_value(true)
+                        .value_parser(["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        Some(("build", args)) => build(args),
+        Some(("update", args)) => update(args),
+        Some(("query", args)) => query(args),
+        _ => unreachable!("clap requires a known subcommand"),
+    }
+}
+
+fn build(args: &ArgMatches) -> anyhow::Result<()> {
+    let root = args.get_one::<String>("root").unwrap();
+    let data = Path::new(args.get_one::<String>("data").unwrap());
+    let columns: Vec<&str> = args
+        .get_many::<String>("column")
+        .unwrap()
+        .map(String::as_str)
+        .collect();
+    let granularity = match args.get_one::<String>("granularity").unwrap().as_str() {
+        "row-group" => Granularity::RowGroup,
+        _ => Granularity::File,
+    };
+    let buckets = *args.get_one::<u64>("buckets").unwrap();
+    let max_memory = *args.get_one::<usize>("max-memory").unwrap();
+
+    anyhow::ensure!(
+        !Path::new(root).join("partitions.data").exists(),
+        "there already is an index in '{}'",
+        root
+    );
+    let start = SystemTime::now();
+    let mut index: PersistentIndex<ParquetPartition> =
+        PersistentIndex::try_new(buckets, root.clone())?;
+    let files = find_parquet_files(data)?;
+    let mut indexed_columns = IndexedColumns {
+        granularity,
+        ..Default::default()
+    };
+    for file in &files {
+        indexed_columns.add_file(file, &columns)?;
+    }
+    // Written before the first segment, so an index that is only partially built can
+    // still be queried and completed by `update`.
+    indexed_columns.store(Path::new(root))?;
+    for file in &files {
+        index_parquet_file(&mut index, file, &columns, granularity)?;
+        if index.estimate_mem_size() > max_memory {
+            index.persist()?;
+        }
+    }
+    index.persist()?;
+
+    println!("files:      {}", files.len());
+    println!("partitions: {}", index.num_partitions());
+    println!("elements:   {}", index.elements());
+    println!("slots:      {}", index.num_slots());
+    println!("segments:   {}", index.num_segments());
+    println!("disk size:  {} bytes", disk_size(Path::new(root))?);
+    println!("duration:   {:?}", start.elapsed()?);
+    Ok(())
+}
+
+fn update(args: &ArgMatches) -> anyhow::Result<()> {
+    let root = args.get_one::<String>("root").unwrap();
+    let data = Path::new(args.get_one::<String>("data").unwrap());
+
+    let start = SystemTime::now();
+    let mut indexed_columns = IndexedColumns::load(Path::new(root))?;
+    let mut index = PersistentIndex::<ParquetPartition>::try_load_for_writing(root.clone())?;
+
+    let summary = update_parquet(&mut index, data, &mut indexed_columns)?;
+    index.persist()?;
+    if args.contains_id("compact") {
+        index.compact()?;
+    }
+    indexed_columns.store(Path::new(root))?;
+
+    println!("added:      {} files", summary.added.len());
+    println!("removed:    {} files", summary.removed.len());
+    println!("unchanged:  {} files", summary.unchanged);
+    println!("partitions: {}", index.active_partitions().count());
+    println!("segments:   {}", index.num_segments());
+    println!("disk size:  {} bytes", disk_size(Path::new(root))?);
+    println!("duration:   {:?}", start.elapsed()?);
+    Ok(())
+}
+
+fn query(args: &ArgMatches) -> anyhow::Result<()> {
+    let root = args.get_one::<String>("root").unwrap();
+    let column = args.get_one::<String>("column").unwrap();
+    let value = args.get_one::<String>("value").unwrap();
+
+    let indexed_columns = IndexedColumns::load(Path::new(root))?;
+    let column_type = indexed_columns.columns.get(column).copied();
+    let value_type = match (args.get_one::<ValueType>("type"), column_type) {
+        (_, None) => anyhow::bail!("column '{}' is not indexed in '{}'", column, root),
+        (Some(value_type), _) => *value_type,
+        (None, Some(column_type)) => column_type,
+    };
+    let value = value_type.parse(value)?;
+    let index = PersistentIndex::<ParquetPartition>::try_load_from_disk(root.clone())?;
+    let result = query_parquet(&index, column, &value)?;
+
+    if args.get_one::<String>("format").unwrap() == "json" {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        for p in &result.partitions {
+            match p.row_group {
+                Some(row_group) => println!("{} (row group {})", p.path.display(), row_group),
+                None => println!("{}", p.path.display()),
+            }
+        }
+        eprintln!(
+            "{} of {} partitions, {} pruned by statistics, {} by the index",
+            result.partitions.len(),
+            result.total,
+            result.pruned_by_statistics,
+            result.pruned_by_index
+        );
+    }
+    Ok(())
+}
+
+/// The size of all files below `path`.
+fn disk_size(path: &Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            disk_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
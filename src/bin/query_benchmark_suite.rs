@@ -21,7 +21,7 @@ fn run_single(
     partitions: u64,
     elements: u64,
     buckets: u64,
-    parallelism: &Vec<usize>,
+    parallelism: &[usize],
     time_limit: Duration,
 ) -> anyhow::Result<Vec<BenchmarkResult>> {
     let index_root = format!(
@@ -29,20 +29,20 @@ fn run_single(
         partitions, elements, buckets
     );
     if PathBuf::from(&index_root).exists() {
-        let _ = fs::remove_dir_all(&index_root)?;
+        fs::remove_dir_all(&index_root)?;
     }
     eprintln!(
         "[query benchmark]: creating p = {}, e = {}, b = {} at {}",
         partitions, elements, buckets, index_root
     );
-    create_index(&index_root, partitions, elements, buckets)?;
+    create_index::<u16>(&index_root, partitions, elements, buckets)?;
     let index = PersistentIndex::<BenchmarkPartition>::try_load_from_disk(index_root.to_string())?;
     // using the same index to run queries with different levels of parallelism
     let results = parallelism
-        .into_iter()
+        .iter()
         .map(|p| run_benchmark(&index, time_limit, *p).expect("waddabadda"))
         .collect();
-    let _ = fs::remove_dir_all(index_root)?;
+    fs::remove_dir_all(index_root)?;
     Ok(results)
 }
 
@@ -71,7 +71,7 @@ fn main() -> anyhow::Result<()> {
     let _occupancy_conf = BenchmarkConfig {
         partitions: vec![1],
         elements: vec![100000],
-        buckets: (1..1000).into_iter().map(|x| x * 500).collect(),
+        buckets: (1..1000).map(|x| x * 500).collect(),
         parallelism: vec![1],
         time_limit: Duration::from_millis(1),
     };
@@ -83,7 +83,7 @@ fn main() -> anyhow::Result<()> {
         for e in conf.elements.iter() {
             for b in conf.buckets.iter() {
                 // benchmark with more then 100 * 10^9 elements does not fit on our disk
-                if p * e <= 100 * 1000 * 1000 * 1000 && e > &b {
+                if p * e <= 100 * 1000 * 1000 * 1000 && e > b {
                     let mut run_results =
                         run_single(p, *e, *b, &conf.parallelism, conf.time_limit)?;
                     benchmark_results.append(&mut run_results);
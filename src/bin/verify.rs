@@ -0,0 +1,22 @@
+use partition_index::{benchmarks::BenchmarkPartition, index::poc::verify};
+
+/// Check a persisted benchmark index and print a JSON report.
+/// With `--values`, additionally regenerate the values of every partition and check
+/// that the index yields the partition for each of them.
+fn main() -> anyhow::Result<()> {
+    use std::env;
+    let args: Vec<String> = env::args().collect();
+    let index_root = &args[1];
+    let report = match args.get(2).map(String::as_str) {
+        None => verify::verify::<BenchmarkPartition>(index_root),
+        Some("--values") => verify::verify_with_values(index_root, |p: &BenchmarkPartition| {
+            p.start..(p.start + p.length)
+        }),
+        Some(other) => anyhow::bail!("unknown option '{}', use '--values'", other),
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -25,8 +25,8 @@ impl Filter for PaperBloom {
         let mut key_rot = key;
         let mut iteration = self.d;
         let bits_per_slot = (u64::BITS - self.m.leading_zeros() + 2) as u64;
-        let slots_per_hash = 64 / bits_per_slot as u64;
-        for _hash in 0..(self.d + slots_per_hash - 1) / slots_per_hash {
+        let slots_per_hash = 64 / bits_per_slot;
+        for _hash in 0..self.d.div_ceil(slots_per_hash) {
             hasher.write_u64(key_rot);
             key_rot = hasher.finish();
             for from in 0..std::cmp::min(slots_per_hash, iteration) {
@@ -44,8 +44,8 @@ impl Filter for PaperBloom {
         let mut key_rot = key;
         let mut iteration = self.d;
         let bits_per_slot = (u64::BITS - self.m.leading_zeros() + 2) as u64;
-        let slots_per_hash = 64 / bits_per_slot as u64;
-        for _hash in 0..(self.d + slots_per_hash - 1) / slots_per_hash {
+        let slots_per_hash = 64 / bits_per_slot;
+        for _hash in 0..self.d.div_ceil(slots_per_hash) {
             hasher.write_u64(key_rot);
             key_rot = hasher.finish();
             for from in 0..std::cmp::min(slots_per_hash, iteration) {
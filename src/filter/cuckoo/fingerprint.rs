@@ -0,0 +1,139 @@
+use std::{fmt::Debug, hash::Hash, hash::Hasher};
+
+/// A fingerprint as stored in the slots of cuckoo filters and indexes.
+///
+/// The width trades space for accuracy: a lookup matches the fingerprint in an
+/// occupied slot with a probability of `1 / (2^BITS - 1)`, so each slot adds that
+/// much to the false positive rate. All zero bits mark an empty slot.
+///
+/// Persisted indexes store fingerprints as little-endian integers of
+/// `size_of::<Self>()` bytes and read them back by reinterpreting the bytes, so the
+/// trait is sealed and only implemented for plain integers.
+pub trait Fingerprint:
+    private::Sealed + Copy + Eq + Hash + Debug + Default + Send + Sync + 'static
+{
+    /// The number of significant bits.
+    const BITS: u8;
+
+    /// The content of an empty slot, never a valid fingerprint.
+    const EMPTY: Self;
+
+    /// The lowest `BITS` bits of `bits`.
+    fn from_bits(bits: u64) -> Self;
+
+    fn to_bits(self) -> u64;
+
+    /// Feed the fingerprint into `hasher`, used to derive the alternate bucket.
+    fn hash_into(self, hasher: &mut impl Hasher);
+
+    /// Convert from little-endian to the native byte order.
+    fn from_le(fingerprint: Self) -> Self;
+
+    /// Convert from the native byte order to little-endian.
+    fn to_le(self) -> Self;
+
+    /// A mask of the `BITS` lowest bits.
+    fn mask() -> u64 {
+        u64::MAX >> (64 - u32::from(Self::BITS))
+    }
+
+    /// The first non-zero `BITS` bits of `hash`, starting with the lowest ones, or 1 if
+    /// all of them are zero.
+    fn from_hash(hash: u64) -> Self {
+        let bits = u32::from(Self::BITS);
+        (0..64 / bits)
+            .map(|i| (hash >> (bits * i)) & Self::mask())
+            .find(|chunk| *chunk != 0)
+            .map_or_else(|| Self::from_bits(1), Self::from_bits)
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+macro_rules! integer_fingerprint {
+    ($($t:ty => $write:ident),*) => {
+        $(
+            impl private::Sealed for $t {}
+
+            impl Fingerprint for $t {
+                const BITS: u8 = <$t>::BITS as u8;
+                const EMPTY: Self = 0;
+
+                fn from_bits(bits: u64) -> Self {
+                    bits as $t
+                }
+
+                fn to_bits(self) -> u64 {
+                    self.into()
+                }
+
+                fn hash_into(self, hasher: &mut impl Hasher) {
+                    hasher.$write(self);
+                }
+
+                fn from_le(fingerprint: Self) -> Self {
+                    <$t>::from_le(fingerprint)
+                }
+
+                fn to_le(self) -> Self {
+                    <$t>::to_le(self)
+                }
+            }
+        )*
+    };
+}
+
+integer_fingerprint!(u8 => write_u8, u16 => write_u16, u32 => write_u32);
+
+/// A 12-bit fingerprint, stored in the lower bits of a `u16`. Slots aren't packed, so it
+/// takes as much space as a 16-bit fingerprint in memory and on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct U12(u16);
+
+impl private::Sealed for U12 {}
+
+impl Fingerprint for U12 {
+    const BITS: u8 = 12;
+    const EMPTY: Self = U12(0);
+
+    fn from_bits(bits: u64) -> Self {
+        U12((bits & Self::mask()) as u16)
+    }
+
+    fn to_bits(self) -> u64 {
+        self.0.into()
+    }
+
+    fn hash_into(self, hasher: &mut impl Hasher) {
+        hasher.write_u16(self.0);
+    }
+
+    fn from_le(fingerprint: Self) -> Self {
+        U12(u16::from_le(fingerprint.0))
+    }
+
+    fn to_le(self) -> Self {
+        U12(self.0.to_le())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fingerprint, U12};
+
+    #[test]
+    fn fingerprints_from_hash() {
+        assert_eq!(u8::from_hash(0x1200), 0x12);
+        assert_eq!(u16::from_hash(0xABCD_0000_1234), 0x1234);
+        assert_eq!(u16::from_hash(0xABCD_0000_0000), 0xABCD);
+        assert_eq!(u32::from_hash(0xABCD_0000_0000), 0xABCD);
+        assert_eq!(U12::from_hash(0xABC_000), U12::from_bits(0xABC));
+        assert_eq!(U12::from_hash(0xFFFF_FFFF_F123).to_bits(), 0x123);
+        // never empty
+        assert_eq!(u8::from_hash(0), 1);
+        assert_eq!(U12::from_hash(0xF000_0000_0000_0000).to_bits(), 1);
+    }
+}
@@ -1,26 +1,57 @@
 use crate::filter::{Filter, InsertResult};
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
 
-use super::{bucket, fingerprint, flip_bucket};
+use super::{
+    fingerprint::Fingerprint, BucketCount, HashScheme, StashEntry, DEFAULT_SEED, STASH_SIZE,
+};
 
 #[derive(Debug)]
-pub struct GrowableCuckooFilter {
-    pub(crate) data: Vec<Vec<u16>>, // 16 bit fingerprints, 0 marks invalid entry
-    buckets: u64,
+pub struct GrowableCuckooFilter<F = u16> {
+    pub(crate) data: Vec<Vec<F>>, // fingerprints, 0 marks invalid entry
+    buckets: BucketCount,
     entries_per_bucket: usize,
     elements: u64, // number of fingerprints stored in the filter
+    scheme: HashScheme,
+    rng: Xoshiro256PlusPlus,   // picks the entries to evict
+    stash: Vec<StashEntry<F>>, // fingerprints that fit into neither of their buckets
+    stash_size: usize,
 }
 
 impl GrowableCuckooFilter {
     pub fn new(buckets: u64) -> Self {
+        Self::with_scheme(buckets, HashScheme::default())
+    }
+}
+
+impl<F: Fingerprint> GrowableCuckooFilter<F> {
+    pub fn with_scheme(buckets: impl Into<BucketCount>, scheme: HashScheme) -> Self {
+        let buckets = buckets.into();
         GrowableCuckooFilter {
-            data: vec![vec![]; buckets as usize],
+            data: vec![vec![]; buckets.count() as usize],
             buckets,
             entries_per_bucket: 1,
             elements: 0,
+            scheme,
+            rng: Xoshiro256PlusPlus::seed_from_u64(DEFAULT_SEED),
+            stash: Vec::with_capacity(STASH_SIZE),
+            stash_size: STASH_SIZE,
         }
     }
 
+    /// Seed the generator picking the entries to evict, see [`super::CuckooFilter::with_seed`].
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+        self
+    }
+
+    /// Keep up to `size` fingerprints in the stash, see
+    /// [`super::CuckooFilter::with_stash_size`].
+    pub fn with_stash_size(mut self, size: usize) -> Self {
+        self.stash_size = size;
+        self
+    }
+
     pub fn entries_per_bucket(&self) -> usize {
         self.entries_per_bucket
     }
@@ -30,46 +61,72 @@ impl GrowableCuckooFilter {
     }
 
     pub fn num_buckets(&self) -> u64 {
-        self.buckets
+        self.buckets.count()
     }
 
-    pub fn drain(self) -> Vec<Vec<u16>> {
-        self.data
+    /// The fingerprints that fit into neither of their buckets. They aren't part of the
+    /// buckets returned by [`Self::drain`].
+    pub fn stash(&self) -> &[StashEntry<F>] {
+        &self.stash
     }
 
-    fn try_insert(&mut self, fingerprint: u16, bucket: u64, tries_left: u8) -> InsertResult {
-        assert!(bucket < self.buckets);
-        let entries = &mut self.data[bucket as usize];
+    pub fn drain(self) -> Vec<Vec<F>> {
+        self.data
+    }
 
-        if entries.len() < self.entries_per_bucket {
-            entries.push(fingerprint);
-            self.elements += 1;
-            return InsertResult::Success;
+    /// Insert `fingerprint` into `bucket`. If the stash is full as well, the filter grows
+    /// by one entry per bucket, which also makes room for the stashed fingerprints.
+    fn try_insert(&mut self, fingerprint: F, bucket: u64, max_kicks: u8) -> InsertResult {
+        self.elements += 1;
+        if let Some(homeless) = self.kick(fingerprint, bucket, max_kicks) {
+            if self.stash.len() < self.stash_size {
+                self.stash.push(homeless);
+            } else {
+                self.entries_per_bucket += 1;
+                self.data[homeless.bucket as usize].push(homeless.fingerprint);
+                for entry in std::mem::take(&mut self.stash) {
+                    if let Some(homeless) = self.kick(entry.fingerprint, entry.bucket, max_kicks) {
+                        self.stash.push(homeless);
+                    }
+                }
+            }
         }
+        InsertResult::Success
+    }
 
-        // Insert failed, no space, let's grow
-        if tries_left == 0 {
-            self.entries_per_bucket += 1;
-            entries.push(fingerprint);
-            self.elements += 1;
-            return InsertResult::Success;
+    /// Put `fingerprint` into `bucket`, evicting up to `max_kicks` entries to their
+    /// alternate buckets. Returns the last evicted fingerprint, along with its bucket,
+    /// if it didn't find a free slot.
+    fn kick(
+        &mut self,
+        mut fingerprint: F,
+        mut bucket: u64,
+        max_kicks: u8,
+    ) -> Option<StashEntry<F>> {
+        for kicks_left in (0..=max_kicks).rev() {
+            assert!(bucket < self.buckets.count());
+            let entries = &mut self.data[bucket as usize];
+            if entries.len() < self.entries_per_bucket {
+                entries.push(fingerprint);
+                return None;
+            }
+            if kicks_left == 0 {
+                break;
+            }
+            // Pick a random entry to evict. Non-random selection can lead to cycles.
+            let entry = self.rng.gen_range(0..entries.len());
+            // Replace value, otherwise we immediately find our fingerprint-to-evict
+            let evicted = std::mem::replace(&mut entries[entry], fingerprint);
+            bucket = self.scheme.flip_bucket(evicted, bucket, self.buckets);
+            fingerprint = evicted;
         }
-
-        // Pick a random entry to evict. Non-random selection can lead to cycles.
-        let entry = rand::thread_rng().gen_range(0..entries.len()) as usize;
-        let evicted = entries[entry];
-
-        // Replace value, otherwise we immediately find our fingerprint-to-evict
-        entries[entry] = fingerprint;
-        drop(entries); // recursive mut self call requires dropping our &mut bucket
-        self.try_insert(
-            evicted,
-            flip_bucket(evicted, bucket, self.buckets),
-            tries_left - 1,
-        )
+        Some(StashEntry {
+            bucket,
+            fingerprint,
+        })
     }
 
-    fn find_in_bucket(&self, fingerprint: u16, bucket: u64) -> bool {
+    fn find_in_bucket(&self, fingerprint: F, bucket: u64) -> bool {
         for entry in &self.data[bucket as usize] {
             if *entry == fingerprint {
                 return true;
@@ -77,14 +134,23 @@ impl GrowableCuckooFilter {
         }
         false
     }
+
+    /// Whether `fingerprint` is in one of its buckets or in the stash.
+    fn find(&self, fingerprint: F, bucket: u64, alt: u64) -> bool {
+        self.find_in_bucket(fingerprint, bucket)
+            || self.find_in_bucket(fingerprint, alt)
+            || self
+                .stash
+                .iter()
+                .any(|entry| entry.matches(fingerprint, bucket, alt))
+    }
 }
 
-impl Filter for GrowableCuckooFilter {
+impl<F: Fingerprint> Filter for GrowableCuckooFilter<F> {
     fn insert(&mut self, key: u64) -> InsertResult {
-        let fingerprint = fingerprint(key);
-        let bucket = bucket(key, self.buckets);
-        let other = flip_bucket(fingerprint, bucket, self.buckets);
-        if self.find_in_bucket(fingerprint, bucket) || self.find_in_bucket(fingerprint, other) {
+        let (fingerprint, bucket) = self.scheme.locate(key, self.buckets);
+        let other = self.scheme.flip_bucket(fingerprint, bucket, self.buckets);
+        if self.find(fingerprint, bucket, other) {
             InsertResult::Duplicate
         } else if self.data[other as usize].len() < self.entries_per_bucket {
             self.try_insert(fingerprint, other, 63)
@@ -94,10 +160,9 @@ impl Filter for GrowableCuckooFilter {
     }
 
     fn contains(&self, key: u64) -> bool {
-        let fingerprint = fingerprint(key);
-        let bucket = bucket(key, self.buckets);
-        let alt = flip_bucket(fingerprint, bucket, self.buckets);
-        self.find_in_bucket(fingerprint, bucket) || self.find_in_bucket(fingerprint, alt)
+        let (fingerprint, bucket) = self.scheme.locate(key, self.buckets);
+        let alt = self.scheme.flip_bucket(fingerprint, bucket, self.buckets);
+        self.find(fingerprint, bucket, alt)
     }
 }
 
@@ -128,6 +193,14 @@ mod tests {
         check_false_negatives(&mut pb, 0..11);
     }
 
+    #[test]
+    fn no_false_negatives_when_growing() {
+        let mut pb = GrowableCuckooFilter::new(64);
+
+        fill_from_range(&mut pb, 0..1000);
+        check_false_negatives(&mut pb, 0..1000);
+    }
+
     #[test]
     fn verify_false_positive_rate() {
         const SAMPLE: u64 = 100_000;
@@ -152,25 +225,25 @@ mod occupancy_tests {
 
     use super::GrowableCuckooFilter;
 
-    /// insert values into a cuckoo filter until it fails
+    /// insert values into a cuckoo filter until it grows, returning the share of
+    /// occupied slots, not counting the stash
     fn data_density(buckets: u64, entries_per_bucket: usize) -> f64 {
         let mut pb = GrowableCuckooFilter::new(buckets);
         let max_entries = buckets * entries_per_bucket as u64;
+        let mut in_buckets = 0;
         for i in 0..max_entries {
+            in_buckets = pb.elements - pb.stash().len() as u64;
             pb.insert(i);
-            // pb.try_insert(fingerprint(i), hash(i) % buckets, u8::MAX);
             // break as soon as we cross the desired number of entries per bucket
             if pb.entries_per_bucket > entries_per_bucket {
                 break;
             }
         }
-        (pb.elements - 1) as f64 / max_entries as f64
+        in_buckets as f64 / max_entries as f64
     }
 
-    // 2^16, 2^17, ... gives a lot of fingerprint clashes. I think that's because our
-    // `fingerprint(key)` and `hash(key)` are basically the same function + % 2^16
-    // leads to same fingerprints consistenly hitting the same buckets.
-    // Possible fix: hash differently, possibly by initializing hashers differently.
+    // 2^16, 2^17, ... used to give a lot of fingerprint clashes with the legacy hash
+    // scheme, whose bucket hash of keys and of fingerprints coincide for small keys.
     #[test]
     fn hash_clash() {
         let occupancy = data_density(1 << 16, 1);
@@ -217,4 +290,30 @@ mod occupancy_tests {
         // 98% is what the paper says, but we use 63 instead of 500 eviction attempts
         assert!(occupancy > 0.97, "occupancy == {}, !> 0.97", occupancy);
     }
+    /// the number of values inserted into `pb` before it grows
+    fn inserted_before_growth(mut pb: GrowableCuckooFilter) -> u64 {
+        let entries_per_bucket = pb.entries_per_bucket;
+        (0..)
+            .find(|&i| {
+                pb.insert(i);
+                pb.entries_per_bucket > entries_per_bucket
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn stash_delays_growth() {
+        for buckets in [(1 << 10) - 1, 2500] {
+            let build = || GrowableCuckooFilter::new(buckets);
+            let with_stash = inserted_before_growth(build());
+            let without_stash = inserted_before_growth(build().with_stash_size(0));
+            assert!(
+                with_stash > without_stash,
+                "{} buckets: {} !> {}",
+                buckets,
+                with_stash,
+                without_stash
+            );
+        }
+    }
 }
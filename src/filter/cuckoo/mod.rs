@@ -1,70 +1,157 @@
+pub mod fingerprint;
 pub mod growable;
 
 use crate::filter::Filter;
-use rand::Rng;
-use siphasher::sip::SipHasher13;
+use fingerprint::Fingerprint;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use siphasher::{sip::SipHasher13, sip128::Hasher128};
 use std::hash::Hasher;
 
 use super::InsertResult;
 
 #[derive(Debug)]
-pub struct CuckooFilter {
-    data: Vec<u16>, // 16 bit fingerprints, 0 marks invalid entry
-    buckets: u64,
+pub struct CuckooFilter<F = u16> {
+    data: Vec<F>, // fingerprints, 0 marks invalid entry
+    buckets: BucketCount,
     entries_per_bucket: u64,
     items: u64, // number of fingerprints stored in the filter
+    scheme: HashScheme,
+    rng: Xoshiro256PlusPlus,   // picks the entries to evict
+    stash: Vec<StashEntry<F>>, // fingerprints that fit into neither of their buckets
+    stash_size: usize,
 }
 
+/// A fingerprint that fit into neither of its buckets, along with one of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StashEntry<F> {
+    pub bucket: u64,
+    pub fingerprint: F,
+}
+
+impl<F: PartialEq> StashEntry<F> {
+    /// Whether the entry holds `fingerprint`, stashed away from `bucket` or `alt`.
+    pub fn matches(&self, fingerprint: F, bucket: u64, alt: u64) -> bool {
+        self.fingerprint == fingerprint && (self.bucket == bucket || self.bucket == alt)
+    }
+}
+
+/// The number of fingerprints a filter keeps aside by default when a chain of evictions
+/// ends without a free slot, before rejecting inserts (or growing, for growable filters).
+/// Stashed fingerprints are compared on every lookup, so the stash is kept small.
+pub const STASH_SIZE: usize = 4;
+
+/// The seed of the generator picking entries to evict, unless a filter is given another
+/// one. Filling filters with the same keys in the same order yields the same layout.
+pub const DEFAULT_SEED: u64 = 0x5eed_c0c0_0f11_7e55;
+
 // lingo:
 // - bucket: as in the cuckoo paper, a list of entries. A value can be in one of two buckets.
 // - entry: entries form a bucket. The number of entries per bucket is fixed via constructor arg.
 // - slot: a single place in the array of fingerprints, containing one or zero fingerprints.
 impl CuckooFilter {
     pub fn new(buckets: u64, buckets_per_entry: u64) -> Self {
+        Self::with_scheme(buckets, buckets_per_entry, HashScheme::default())
+    }
+}
+
+impl<F: Fingerprint> CuckooFilter<F> {
+    /// Create a filter hashing keys with `scheme`. `buckets` is either the number of
+    /// buckets, reduced with [`BucketReduction::Modulo`], or a [`BucketCount`].
+    pub fn with_scheme(
+        buckets: impl Into<BucketCount>,
+        buckets_per_entry: u64,
+        scheme: HashScheme,
+    ) -> Self {
+        let buckets = buckets.into();
         CuckooFilter {
-            data: vec![0; (buckets * buckets_per_entry).try_into().unwrap()],
+            data: vec![F::EMPTY; (buckets.count() * buckets_per_entry).try_into().unwrap()],
             buckets,
             entries_per_bucket: buckets_per_entry,
             items: 0,
+            scheme,
+            rng: Xoshiro256PlusPlus::seed_from_u64(DEFAULT_SEED),
+            stash: Vec::with_capacity(STASH_SIZE),
+            stash_size: STASH_SIZE,
         }
     }
 
-    fn try_insert(&mut self, fingerprint: u16, bucket: u64, tries_left: u8) -> InsertResult {
-        assert!(bucket < self.buckets, "{} < {}", bucket, self.buckets);
-        let start_slot = (bucket * self.entries_per_bucket) as usize;
-        for b in start_slot..(start_slot + self.entries_per_bucket as usize) {
-            if self.data[b] == fingerprint {
-                return InsertResult::Duplicate;
+    /// Seed the generator picking the entries to evict when both buckets of a key are
+    /// full, instead of using [`DEFAULT_SEED`].
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+        self
+    }
+
+    /// Keep up to `size` fingerprints in the stash instead of [`STASH_SIZE`], none
+    /// disables the stash.
+    pub fn with_stash_size(mut self, size: usize) -> Self {
+        self.stash_size = size;
+        self
+    }
+
+    /// Insert `fingerprint` into `bucket`, evicting up to `max_kicks` entries to their
+    /// alternate buckets. The last evicted fingerprint goes to the stash if it doesn't
+    /// find a free slot either; if the stash is full, all evictions are undone.
+    fn try_insert(&mut self, mut fingerprint: F, mut bucket: u64, max_kicks: u8) -> InsertResult {
+        // the evicted entries, to restore them if the insert is rejected
+        let mut evictions = vec![];
+        for kicks_left in (0..=max_kicks).rev() {
+            assert!(
+                bucket < self.buckets.count(),
+                "{} < {}",
+                bucket,
+                self.buckets.count()
+            );
+            let start_slot = (bucket * self.entries_per_bucket) as usize;
+            for b in start_slot..(start_slot + self.entries_per_bucket as usize) {
+                if self.data[b] == fingerprint {
+                    return InsertResult::Duplicate;
+                }
+                if self.data[b] == F::EMPTY {
+                    self.data[b] = fingerprint;
+                    self.items += 1;
+                    return InsertResult::Success;
+                }
             }
-            if self.data[b] == 0 {
-                self.data[b] = fingerprint;
-                self.items += 1;
-                return InsertResult::Success;
+            if kicks_left == 0 {
+                break;
             }
+            // Evicting the first entry. Determined by a fair dice roll.
+            let slot = start_slot + self.rng.gen_range(0..self.entries_per_bucket) as usize;
+            let evicted = self.data[slot];
+            // replace already, otherwise we immediately find our fingerprint-to-evict
+            self.data[slot] = fingerprint;
+            evictions.push((slot, evicted));
+            bucket = self.scheme.flip_bucket(evicted, bucket, self.buckets);
+            fingerprint = evicted;
         }
-        if tries_left == 0 {
-            return InsertResult::Rejected;
+        if self.stash.len() < self.stash_size {
+            self.stash.push(StashEntry {
+                bucket,
+                fingerprint,
+            });
+            self.items += 1;
+            return InsertResult::Success;
         }
-        // Evicting the first entry. Determined by a fair dice roll.
-        let slot = start_slot + rand::thread_rng().gen_range(0..self.entries_per_bucket) as usize;
-        let evicted = self.data[slot];
-        // replace already, otherwise we immediately find our fingerprint-to-evict
-        self.data[slot] = fingerprint;
-        let result = self.try_insert(
-            evicted,
-            flip_bucket(evicted, bucket, self.buckets),
-            tries_left - 1,
-        );
-        if result == InsertResult::Rejected {
+        for (slot, evicted) in evictions.into_iter().rev() {
             self.data[slot] = evicted; // restore previous entry
-            InsertResult::Rejected
-        } else {
-            result
         }
+        InsertResult::Rejected
     }
 
-    fn find_in_bucket(&self, fingerprint: u16, bucket: u64) -> bool {
-        assert!(bucket < self.buckets);
+    /// Whether `fingerprint` is in one of its buckets or in the stash.
+    fn find(&self, fingerprint: F, bucket: u64, alt: u64) -> bool {
+        self.find_in_bucket(fingerprint, bucket)
+            || self.find_in_bucket(fingerprint, alt)
+            || self
+                .stash
+                .iter()
+                .any(|entry| entry.matches(fingerprint, bucket, alt))
+    }
+
+    fn find_in_bucket(&self, fingerprint: F, bucket: u64) -> bool {
+        assert!(bucket < self.buckets.count());
         let start_slot = (bucket * self.entries_per_bucket) as usize;
         for b in start_slot..(start_slot + self.entries_per_bucket as usize) {
             if self.data[b] == fingerprint {
@@ -75,32 +162,187 @@ impl CuckooFilter {
     }
 }
 
+/// How keys are mapped to a fingerprint and their two buckets. Filters and indexes
+/// only find keys that were inserted with the same scheme, so persisted indexes
+/// record the scheme they were written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashScheme {
+    /// The fingerprint is a keyed SipHash-1-3 of the key, the bucket an unkeyed
+    /// SipHash-1-3 of the key, and the alternate bucket derived from the same unkeyed
+    /// hash of the fingerprint, so a key below 2^16 shares its bucket hash with the
+    /// fingerprint of the same value.
+    Legacy,
+    /// A single keyed 128-bit SipHash-1-3 of the key, with one half determining the
+    /// fingerprint and the other the bucket. The alternate bucket is derived from a
+    /// hash of the fingerprint with different keys.
+    #[default]
+    Split128,
+}
+
+impl HashScheme {
+    /// The fingerprint and the primary bucket of `key`.
+    #[inline]
+    pub fn locate<F: Fingerprint>(self, key: u64, buckets: impl Into<BucketCount>) -> (F, u64) {
+        let buckets = buckets.into();
+        match self {
+            HashScheme::Legacy => (legacy_fingerprint(key), buckets.reduce(hash_u64(key))),
+            HashScheme::Split128 => {
+                let mut hasher =
+                    siphasher::sip128::SipHasher13::new_with_keys(SPLIT_KEY0, SPLIT_KEY1);
+                hasher.write_u64(key);
+                let hash = hasher.finish128();
+                (F::from_hash(hash.h1), buckets.reduce(hash.h2))
+            }
+        }
+    }
+
+    /// The fingerprint for the given key.
+    /// 0 is an invalid fingerprint as it demarks an empty entry, so valid
+    /// fingerprints have a range of [1, 2^BITS).
+    pub fn fingerprint<F: Fingerprint>(self, key: u64) -> F {
+        self.locate(key, 1).0
+    }
+
+    pub fn bucket(self, key: u64, buckets: impl Into<BucketCount>) -> u64 {
+        self.locate::<u16>(key, buckets).1
+    }
+
+    /// The other bucket of a fingerprint stored in `bucket`.
+    pub fn flip_bucket<F: Fingerprint>(
+        self,
+        fingerprint: F,
+        bucket: u64,
+        buckets: impl Into<BucketCount>,
+    ) -> u64 {
+        let buckets = buckets.into();
+        assert!(
+            bucket < buckets.count(),
+            "bucket {} >= max of {}",
+            bucket,
+            buckets.count()
+        );
+        let fp_hash = match self {
+            HashScheme::Legacy => hash_u64(fingerprint.to_bits()),
+            HashScheme::Split128 => {
+                let mut hasher = SipHasher13::new_with_keys(FLIP_KEY0, FLIP_KEY1);
+                fingerprint.hash_into(&mut hasher);
+                hasher.finish()
+            }
+        };
+        buckets.alternate(fp_hash, bucket)
+    }
+}
+
+/// How a hash is reduced to one of the buckets, and how the alternate bucket of a
+/// fingerprint is derived from a hash of the fingerprint and the bucket it's stored in.
+/// Keys are only found with the reduction they were inserted with, persisted indexes
+/// record it along with the hash scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BucketReduction {
+    /// `hash % buckets`, with the alternate bucket `(hash(fp) - bucket) % buckets`.
+    /// Works for any number of buckets, but costs two 64-bit divisions per lookup.
+    #[default]
+    Modulo,
+    /// The lower bits of the hash, with the alternate bucket `bucket ^ (hash(fp) & mask)`.
+    /// Requires a power of two number of buckets.
+    PowerOfTwo,
+    /// The upper 64 bits of `hash * buckets` (Lemire's fast range), with the alternate
+    /// bucket `fast_range(hash(fp)) - bucket`, wrapping around at the number of buckets.
+    /// Works for any number of buckets without dividing.
+    FastRange,
+}
+
+/// The number of buckets of a filter or index, along with the reduction mapping hashes
+/// to them. A plain number of buckets uses [`BucketReduction::Modulo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BucketCount {
+    count: u64,
+    reduction: BucketReduction,
+}
+
+impl BucketCount {
+    /// Panics if `count` is zero, or not a power of two for [`BucketReduction::PowerOfTwo`].
+    pub fn new(count: u64, reduction: BucketReduction) -> Self {
+        Self::try_new(count, reduction)
+            .unwrap_or_else(|| panic!("{} buckets can't be reduced with {:?}", count, reduction))
+    }
+
+    /// Like `new`, returning `None` for a number of buckets the reduction can't be used
+    /// with.
+    pub fn try_new(count: u64, reduction: BucketReduction) -> Option<Self> {
+        let valid = match reduction {
+            BucketReduction::Modulo | BucketReduction::FastRange => count > 0,
+            BucketReduction::PowerOfTwo => count.is_power_of_two(),
+        };
+        valid.then_some(Self { count, reduction })
+    }
+
+    pub fn count(self) -> u64 {
+        self.count
+    }
+
+    pub fn reduction(self) -> BucketReduction {
+        self.reduction
+    }
+
+    /// The bucket of a key with the given hash.
+    #[inline]
+    fn reduce(self, hash: u64) -> u64 {
+        match self.reduction {
+            BucketReduction::Modulo => hash % self.count,
+            BucketReduction::PowerOfTwo => hash & (self.count - 1),
+            BucketReduction::FastRange => fast_range(hash, self.count),
+        }
+    }
+
+    /// The other bucket of a fingerprint with hash `fp_hash` that is stored in `bucket`.
+    /// Applied twice, it yields `bucket` again.
+    #[inline]
+    fn alternate(self, fp_hash: u64, bucket: u64) -> u64 {
+        match self.reduction {
+            BucketReduction::Modulo => fp_hash.wrapping_sub(bucket) % self.count,
+            BucketReduction::PowerOfTwo => bucket ^ (fp_hash & (self.count - 1)),
+            BucketReduction::FastRange => {
+                let offset = fast_range(fp_hash, self.count);
+                if offset >= bucket {
+                    offset - bucket
+                } else {
+                    offset + self.count - bucket
+                }
+            }
+        }
+    }
+}
+
+impl From<u64> for BucketCount {
+    fn from(count: u64) -> Self {
+        Self::new(count, BucketReduction::Modulo)
+    }
+}
+
+/// Maps `hash` to `[0, n)` with a multiplication instead of a division.
 #[inline]
-/// Create a 16-bit fingerprint for the given key_rot
-/// 0 is an invalid fingerprint as it demarks an empty entry, so another
-/// round of hashing is done until a valid fingerprint is created.
-/// Valid fingerprints have a range of [1, 65536)
-pub fn fingerprint(key: u64) -> u16 {
+fn fast_range(hash: u64, n: u64) -> u64 {
+    ((u128::from(hash) * u128::from(n)) >> 64) as u64
+}
+
+const SPLIT_KEY0: u64 = 0x6375_636b_6f6f_2d62;
+const SPLIT_KEY1: u64 = 0x7563_6b65_742d_6670;
+const FLIP_KEY0: u64 = 0x666c_6970_2d62_7563;
+const FLIP_KEY1: u64 = 0x6b65_742d_6b65_7931;
+
+/// Re-hashes the key until the lower `F::BITS` bits are not zero.
+fn legacy_fingerprint<F: Fingerprint>(key: u64) -> F {
     let mut hasher = SipHasher13::new_with_keys(329, 4242432435);
     let mut key_rot = key;
     loop {
         hasher.write_u64(key_rot);
         key_rot = hasher.finish();
-        if key_rot & 0xFFFF != 0 {
+        if key_rot & F::mask() != 0 {
             break;
         }
     }
-    (key_rot & 0xFFFF) as u16
-}
-
-pub fn bucket(key: u64, buckets: u64) -> u64 {
-    hash_u64(key) % buckets
-}
-
-pub fn flip_bucket(fingerprint: u16, bucket: u64, buckets: u64) -> u64 {
-    assert!(bucket < buckets, "bucket {} >= max of {}", bucket, buckets);
-    let fp_hash = hash_u64(fingerprint.into());
-    fp_hash.wrapping_sub(bucket) % buckets
+    F::from_bits(key_rot)
 }
 
 #[inline]
@@ -110,14 +352,13 @@ fn hash_u64(key: u64) -> u64 {
     hasher.finish()
 }
 
-impl Filter for CuckooFilter {
+impl<F: Fingerprint> Filter for CuckooFilter<F> {
     fn insert(&mut self, key: u64) -> InsertResult {
-        let fingerprint = fingerprint(key);
-        let bucket = bucket(key, self.buckets);
-        let other = flip_bucket(fingerprint, bucket, self.buckets);
-        if self.find_in_bucket(fingerprint, bucket) || self.find_in_bucket(fingerprint, other) {
+        let (fingerprint, bucket) = self.scheme.locate(key, self.buckets);
+        let other = self.scheme.flip_bucket(fingerprint, bucket, self.buckets);
+        if self.find(fingerprint, bucket, other) {
             InsertResult::Duplicate
-        } else if self.find_in_bucket(0, other) {
+        } else if self.find_in_bucket(F::EMPTY, other) {
             self.try_insert(fingerprint, other, u8::MAX)
         } else {
             self.try_insert(fingerprint, bucket, u8::MAX)
@@ -125,16 +366,18 @@ impl Filter for CuckooFilter {
     }
 
     fn contains(&self, key: u64) -> bool {
-        let fingerprint = fingerprint(key);
-        let bucket = bucket(key, self.buckets);
-        let alt = flip_bucket(fingerprint, bucket, self.buckets);
-        self.find_in_bucket(fingerprint, bucket) || self.find_in_bucket(fingerprint, alt)
+        let (fingerprint, bucket) = self.scheme.locate(key, self.buckets);
+        let alt = self.scheme.flip_bucket(fingerprint, bucket, self.buckets);
+        self.find(fingerprint, bucket, alt)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::CuckooFilter;
+    use super::{
+        fingerprint::{Fingerprint, U12},
+        CuckooFilter, HashScheme,
+    };
     use crate::filter::{correctness_tests::*, Filter, InsertResult};
 
     const INPUTS: u64 = 10_000;
@@ -175,21 +418,59 @@ mod tests {
             0.0001
         );
     }
+
+    #[test]
+    fn no_false_negatives_when_full() {
+        let mut pb = CuckooFilter::new(64, 2);
+        let inserted: Vec<u64> = (0..1000)
+            .filter(|key| pb.insert(*key) == InsertResult::Success)
+            .collect();
+        assert_eq!(pb.stash.len(), super::STASH_SIZE);
+        assert_eq!(pb.items, inserted.len() as u64);
+        assert!(inserted.iter().all(|key| pb.contains(*key)));
+    }
+
+    fn false_positive_rate<F: Fingerprint>() -> f64 {
+        let mut pb: CuckooFilter<F> = CuckooFilter::with_scheme(5000, 4, HashScheme::default());
+        fill_from_range(&mut pb, 0..INPUTS);
+        check_false_negatives(&mut pb, 0..INPUTS);
+        estimate_false_positive_rate(&mut pb, INPUTS..INPUTS + 100_000)
+    }
+
+    #[test]
+    fn false_positive_rate_by_fingerprint_width() {
+        let rates = [
+            false_positive_rate::<u8>(),
+            false_positive_rate::<U12>(),
+            false_positive_rate::<u16>(),
+            false_positive_rate::<u32>(),
+        ];
+        // 2 buckets with 2 entries on average, each matching with 1 / (2^bits - 1)
+        assert!(rates[0] > 0.005 && rates[0] < 0.03, "8 bits: {}", rates[0]);
+        assert!(rates[1] < 0.002, "12 bits: {}", rates[1]);
+        assert!(rates[0] > rates[1] && rates[1] > rates[2] && rates[2] >= rates[3]);
+    }
 }
 
 #[cfg(test)]
 mod occupancy_tests {
-    use crate::filter::{cuckoo::fingerprint, InsertResult};
+    use crate::filter::InsertResult;
 
-    use super::{bucket, CuckooFilter};
+    use super::{CuckooFilter, HashScheme};
 
     /// insert values into a cuckoo filter until it fails
     fn data_density(buckets: u64, entries_per_bucket: u64) -> f64 {
-        let mut pb = CuckooFilter::new(buckets, entries_per_bucket);
+        density_of(
+            CuckooFilter::new(buckets, entries_per_bucket),
+            buckets,
+            entries_per_bucket,
+        )
+    }
+
+    fn density_of(mut pb: CuckooFilter, buckets: u64, entries_per_bucket: u64) -> f64 {
         let mut inserted = 0;
         for i in 0..(buckets * entries_per_bucket + 1) {
-            let fingerprint = fingerprint(i);
-            let bucket = bucket(i, buckets);
+            let (fingerprint, bucket) = HashScheme::default().locate(i, buckets);
             if pb.try_insert(fingerprint, bucket, u8::MAX) == InsertResult::Rejected {
                 break;
             }
@@ -200,14 +481,21 @@ mod occupancy_tests {
 
     #[test]
     fn one_entry() {
-        // 2^16, 2^17, ... gives a lot of fingerprint clashes. I think that's because our
-        // `fingerprint(key)` and `hash(key)` are basically the same function + % 2^16
-        // leads to same fingerprints consistenly hitting the same buckets.
-        let occupancy = data_density(1 << 5 - 1, 1);
+        // 2^16, 2^17, ... used to give a lot of fingerprint clashes with the legacy hash
+        // scheme, whose bucket hash of keys and of fingerprints coincide for small keys.
+        let occupancy = data_density(1 << (5 - 1), 1);
         // 50% is what the paper says, not sure how I got 70 ;)
         assert!(occupancy > 0.70, "occupancy == {}, !> 0.70", occupancy);
     }
 
+    #[test]
+    fn one_entry_1k_buckets() {
+        // this has space for 1024 fingerprints
+        let occupancy = data_density(1 << 10, 1);
+        // 50% is what the paper says
+        assert!(occupancy > 0.50, "occupancy == {}, !> 0.50", occupancy);
+    }
+
     #[test]
     /// according to the paper, occupancy should be 0.84
     fn two_entries() {
@@ -232,20 +520,61 @@ mod occupancy_tests {
         // 98% is what the paper says
         assert!(occupancy > 0.97, "occupancy == {}, !> 0.97", occupancy);
     }
+
+    #[test]
+    fn stash_raises_occupancy() {
+        for entries_per_bucket in [1, 2, 4] {
+            let build = || CuckooFilter::new(1 << 10, entries_per_bucket);
+            let with_stash = density_of(build(), 1 << 10, entries_per_bucket);
+            let without_stash = density_of(build().with_stash_size(0), 1 << 10, entries_per_bucket);
+            assert!(
+                with_stash > without_stash,
+                "{} entries: {} !> {}",
+                entries_per_bucket,
+                with_stash,
+                without_stash
+            );
+        }
+    }
 }
 
 #[cfg(test)]
 mod prop_tests {
 
-    use crate::filter::cuckoo::{bucket, fingerprint, flip_bucket};
+    use crate::filter::cuckoo::{
+        fingerprint::{Fingerprint, U12},
+        BucketCount, BucketReduction, HashScheme,
+    };
     use proptest::prelude::*;
 
+    fn bucket_roundtrip_of<F: Fingerprint>(key: u64, buckets: BucketCount) {
+        for scheme in [HashScheme::Legacy, HashScheme::Split128] {
+            let (fingerprint, b0): (F, _) = scheme.locate(key, buckets);
+            assert_ne!(fingerprint, F::EMPTY);
+            assert!(b0 < buckets.count());
+            let b1 = scheme.flip_bucket(fingerprint, b0, buckets);
+            assert!(b1 < buckets.count());
+            let b2 = scheme.flip_bucket(fingerprint, b1, buckets);
+            assert_eq!(b0, b2, "b0 != b2: {} != {}", b0, b2);
+        }
+    }
+
     fn bucket_roundtrip(key: u64, buckets: u64) {
-        let fingerprint = fingerprint(key);
-        let b0 = bucket(key, buckets);
-        let b1 = flip_bucket(fingerprint, b0, buckets);
-        let b2 = flip_bucket(fingerprint, b1, buckets);
-        assert_eq!(b0, b2, "b0 != b2: {} != {}", b0, b2);
+        let reductions = [
+            BucketReduction::Modulo,
+            BucketReduction::PowerOfTwo,
+            BucketReduction::FastRange,
+        ];
+        // the power of two reduction only applies to some of the bucket counts
+        for buckets in reductions
+            .into_iter()
+            .filter_map(|reduction| BucketCount::try_new(buckets, reduction))
+        {
+            bucket_roundtrip_of::<u8>(key, buckets);
+            bucket_roundtrip_of::<U12>(key, buckets);
+            bucket_roundtrip_of::<u16>(key, buckets);
+            bucket_roundtrip_of::<u32>(key, buckets);
+        }
     }
 
     proptest! {
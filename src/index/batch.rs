@@ -0,0 +1,95 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::filter::cuckoo::{fingerprint::Fingerprint, BucketCount, HashScheme};
+
+use super::{in_memory::PartitionInfo, PartitionId};
+
+/// The values of a batch query, grouped by the buckets they have to be looked up in,
+/// so each bucket is only read once for the whole batch.
+pub(crate) struct QueryBatch<F> {
+    num_values: usize,
+    // candidate bucket -> fingerprint -> positions of the values with that fingerprint
+    buckets: Vec<(u64, HashMap<F, Vec<usize>>)>,
+}
+
+impl<F: Fingerprint> QueryBatch<F> {
+    pub(crate) fn new(values: &[u64], num_buckets: BucketCount, scheme: HashScheme) -> Self {
+        let mut buckets: BTreeMap<u64, HashMap<F, Vec<usize>>> = BTreeMap::new();
+        for (pos, value) in values.iter().enumerate() {
+            let (fingerprint, bucket1) = scheme.locate(*value, num_buckets);
+            let bucket2 = scheme.flip_bucket(fingerprint, bucket1, num_buckets);
+            buckets
+                .entry(bucket1)
+                .or_default()
+                .entry(fingerprint)
+                .or_default()
+                .push(pos);
+            if bucket2 != bucket1 {
+                buckets
+                    .entry(bucket2)
+                    .or_default()
+                    .entry(fingerprint)
+                    .or_default()
+                    .push(pos);
+            }
+        }
+        Self {
+            num_values: values.len(),
+            buckets: buckets.into_iter().collect(),
+        }
+    }
+
+    /// The buckets to read, along with the fingerprints to look up in each of them.
+    pub(crate) fn buckets(&self) -> &[(u64, HashMap<F, Vec<usize>>)] {
+        &self.buckets
+    }
+
+    /// Collect the matches of all buckets into the result of each value: the IDs of the
+    /// matching partitions, each ID once and in ascending order.
+    pub(crate) fn collect(
+        &self,
+        matches: impl IntoIterator<Item = (usize, PartitionId)>,
+    ) -> Vec<Vec<PartitionId>> {
+        let mut results = vec![vec![]; self.num_values];
+        for (pos, id) in matches {
+            results[pos].push(id);
+        }
+        for ids in &mut results {
+            ids.sort_unstable();
+            ids.dedup();
+        }
+        results
+    }
+}
+
+/// Scan the slots of `partitions` in `bucket` for the given fingerprints, along with the
+/// fingerprints the partitions stashed away from it, pushing the position of each
+/// matching value along with the ID of the partition.
+pub(crate) fn scan_bucket<'a, P: 'a, F: Fingerprint>(
+    fingerprints: &HashMap<F, Vec<usize>>,
+    bucket: u64,
+    slots: &[F],
+    partitions: impl Iterator<Item = (usize, &'a PartitionInfo<P>)>,
+    matches: &mut Vec<(usize, PartitionId)>,
+) {
+    let mut pos = 0;
+    for (id, p) in partitions {
+        if p.active {
+            let stashed = p
+                .stash
+                .iter()
+                .filter(|entry| entry.bucket == bucket)
+                .map(|entry| F::from_bits(entry.fingerprint));
+            for fp in slots[pos..pos + p.bucket_size]
+                .iter()
+                .copied()
+                .chain(stashed)
+            {
+                if let Some(values) = fingerprints.get(&fp) {
+                    matches.extend(values.iter().map(|value| (*value, PartitionId(id))));
+                }
+            }
+        }
+        pos += p.bucket_size;
+    }
+}
@@ -1,6 +1,11 @@
-use crate::filter::cuckoo::{bucket, fingerprint, flip_bucket, growable};
+use crate::filter::cuckoo::{
+    fingerprint::Fingerprint, growable, BucketCount, HashScheme, StashEntry, DEFAULT_SEED,
+};
 use crate::filter::Filter;
-use crate::index::{PartitionFilter, PartitionIndex};
+use crate::index::{
+    batch::{scan_bucket, QueryBatch},
+    PartitionFilter, PartitionId, PartitionIndex, PartitionLookup,
+};
 use rayon::prelude::*;
 
 #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -9,31 +14,98 @@ pub struct PartitionInfo<P> {
     pub(crate) bucket_size: usize,
     pub(crate) active: bool,
     pub(crate) elements: u64,
+    // the fingerprints (as bits) that fit into neither of their buckets, checked on
+    // every lookup of the partition
+    pub(crate) stash: Vec<StashEntry<u64>>,
+}
+
+impl<P> PartitionInfo<P> {
+    /// Whether `fingerprint` was stashed away from `bucket`.
+    pub(crate) fn stashed<F: Fingerprint>(&self, fingerprint: F, bucket: u64) -> bool {
+        self.stash.contains(&StashEntry {
+            bucket,
+            fingerprint: fingerprint.to_bits(),
+        })
+    }
+
+    /// The memory taken by the stash.
+    pub(crate) fn stash_mem_size(&self) -> usize {
+        self.stash.len() * std::mem::size_of::<StashEntry<u64>>()
+    }
+}
+
+/// The stash of a partition filter, as kept in the [`PartitionInfo`] of the index.
+fn stash_of<F: Fingerprint>(f: &growable::GrowableCuckooFilter<F>) -> Vec<StashEntry<u64>> {
+    f.stash()
+        .iter()
+        .map(|entry| StashEntry {
+            bucket: entry.bucket,
+            fingerprint: entry.fingerprint.to_bits(),
+        })
+        .collect()
 }
 
 #[derive(Debug, PartialEq, Eq)]
-pub struct CuckooIndex<P> {
+pub struct CuckooIndex<P, F = u16> {
     pub(crate) partitions: Vec<PartitionInfo<P>>,
-    pub(crate) buckets: Vec<Vec<u16>>,
+    pub(crate) buckets: Vec<Vec<F>>,
     pub(crate) slots: usize,
     pub(crate) elements: u64,
+    pub(crate) scheme: HashScheme,
+    pub(crate) bucket_count: BucketCount,
+    // seeds the eviction of the filter built for each partition
+    pub(crate) seed: u64,
 }
 
 impl<P> CuckooIndex<P> {
     pub fn new(buckets: u64) -> Self {
+        Self::with_scheme(buckets, HashScheme::default())
+    }
+}
+
+impl<P, F: Fingerprint> CuckooIndex<P, F> {
+    /// Create an index hashing keys with `scheme` into fingerprints of type `F`.
+    /// `buckets` is either the number of buckets or a [`BucketCount`] choosing how
+    /// hashes are reduced to them.
+    pub fn with_scheme(buckets: impl Into<BucketCount>, scheme: HashScheme) -> Self {
+        let bucket_count = buckets.into();
         Self {
             partitions: vec![],
-            buckets: vec![vec![]; buckets as usize],
+            buckets: vec![vec![]; bucket_count.count() as usize],
             slots: 0,
             elements: 0,
+            scheme,
+            bucket_count,
+            seed: DEFAULT_SEED,
         }
     }
 
+    /// Seed the eviction of the filters built for each partition. Every partition uses
+    /// the same seed, so adding the same values yields the same buckets, no matter
+    /// in which batch a partition was added.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn scheme(&self) -> HashScheme {
+        self.scheme
+    }
+
+    pub fn bucket_count(&self) -> BucketCount {
+        self.bucket_count
+    }
+
     fn index_single_partition(
         &self,
         values: impl Iterator<Item = u64>,
-    ) -> growable::GrowableCuckooFilter {
-        let mut f = growable::GrowableCuckooFilter::new(self.buckets.len() as u64);
+    ) -> growable::GrowableCuckooFilter<F> {
+        let mut f = growable::GrowableCuckooFilter::with_scheme(self.bucket_count, self.scheme)
+            .with_seed(self.seed);
         for v in values.into_iter() {
             f.insert(v);
         }
@@ -41,33 +113,62 @@ impl<P> CuckooIndex<P> {
     }
 }
 
-impl<P> PartitionFilter<P> for CuckooIndex<P>
-where
-    P: Clone,
-{
-    fn query(&self, key: u64) -> anyhow::Result<Vec<P>> {
-        let fingerprint = fingerprint(key);
-        let bucket1 = bucket(key, self.buckets.len() as u64);
-        let bucket2 = flip_bucket(fingerprint, bucket1, self.buckets.len() as u64) as usize;
+impl<P, F> PartitionLookup<P> for CuckooIndex<P, F> {
+    fn partition(&self, id: PartitionId) -> Option<&P> {
+        self.partitions.get(id.0).map(|p| &p.partition)
+    }
+}
+
+impl<P, F: Fingerprint> PartitionFilter<P> for CuckooIndex<P, F> {
+    fn query_ids(&self, key: u64) -> anyhow::Result<Vec<PartitionId>> {
+        let (fingerprint, bucket1) = self.scheme.locate(key, self.bucket_count);
+        let bucket2 = self
+            .scheme
+            .flip_bucket(fingerprint, bucket1, self.bucket_count) as usize;
         let mut pos = 0;
         let mut result = vec![];
-        for p in &self.partitions {
-            if p.active {
-                for l in 0..p.bucket_size {
-                    if self.buckets[bucket1 as usize][pos + l] == fingerprint
-                        || self.buckets[bucket2][pos + l] == fingerprint
-                    {
-                        result.push(p.partition.clone());
-                    }
-                }
+        for (id, p) in self.partitions.iter().enumerate() {
+            // yield each partition once, even if several of its slots match
+            if p.active
+                && ((pos..pos + p.bucket_size).any(|l| {
+                    self.buckets[bucket1 as usize][l] == fingerprint
+                        || self.buckets[bucket2][l] == fingerprint
+                }) || p.stashed(fingerprint, bucket1)
+                    || p.stashed(fingerprint, bucket2 as u64))
+            {
+                result.push(PartitionId(id));
             }
             pos += p.bucket_size;
         }
         Ok(result)
     }
+
+    fn query_many_ids(&self, values: &[u64]) -> anyhow::Result<Vec<Vec<PartitionId>>>
+    where
+        P: Send + Sync,
+        Self: Sync,
+    {
+        let batch = QueryBatch::new(values, self.bucket_count, self.scheme);
+        let matches: Vec<_> = batch
+            .buckets()
+            .par_iter()
+            .flat_map_iter(|(bucket, fingerprints)| {
+                let mut matches = vec![];
+                scan_bucket(
+                    fingerprints,
+                    *bucket,
+                    &self.buckets[*bucket as usize],
+                    self.partitions.iter().enumerate(),
+                    &mut matches,
+                );
+                matches
+            })
+            .collect();
+        Ok(batch.collect(matches))
+    }
 }
 
-impl<P> PartitionIndex<P> for CuckooIndex<P>
+impl<P, F: Fingerprint> PartitionIndex<P> for CuckooIndex<P, F>
 where
     P: PartialEq,
 {
@@ -78,6 +179,7 @@ where
             bucket_size: f.entries_per_bucket(),
             active: true,
             elements: f.elements(),
+            stash: stash_of(&f),
         });
         self.slots += f.entries_per_bucket();
         self.elements += f.elements();
@@ -85,7 +187,7 @@ where
             bucket.append(partition_values);
             if bucket.len() < self.slots {
                 // resize underfull buckets from the partition filter
-                bucket.resize(self.slots, 0);
+                bucket.resize(self.slots, F::EMPTY);
             }
         }
     }
@@ -107,8 +209,8 @@ where
                 bucket.reserve(new_slots);
                 for f in filters.iter() {
                     f.1.data[idx].iter().for_each(|e| bucket.push(*e));
-                    for _ in f.1.data[idx].len() .. f.1.entries_per_bucket() {
-                        bucket.push(0); // place holder for unoccupied slots
+                    for _ in f.1.data[idx].len()..f.1.entries_per_bucket() {
+                        bucket.push(F::EMPTY); // place holder for unoccupied slots
                     }
                 }
             });
@@ -122,6 +224,7 @@ where
                     bucket_size: f.entries_per_bucket(),
                     active: true,
                     elements: f.elements(),
+                    stash: stash_of(&f),
                 }
             })
             .collect();
@@ -129,12 +232,13 @@ where
         Ok(())
     }
 
-    fn remove(&mut self, to_be_removed: &P) {
+    fn remove(&mut self, to_be_removed: &P) -> anyhow::Result<()> {
         for p in self.partitions.iter_mut() {
             if &p.partition == to_be_removed {
                 p.active = false;
             }
         }
+        Ok(())
     }
 }
 
@@ -164,12 +268,12 @@ mod tests {
         // let partitions = &tests::create_test_data(10, (5, 17), SEED)[9..];
         let partitions = &tests::create_test_data(100, (999, 4999), SEED);
         let mut index: CuckooIndex<TestPartition> = CuckooIndex::new(800);
-        tests::fill_index(&mut index, &partitions);
+        tests::fill_index(&mut index, partitions);
 
         for p in partitions {
-            if let Some(first_val) = tests::create_partition_data(&p).next() {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
                 assert!(
-                    index.query(first_val)?.contains(&p),
+                    index.query(first_val)?.contains(p),
                     "querying partitions for '{}' does not yield expected {:?}",
                     first_val,
                     &p.id
@@ -181,12 +285,45 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn yield_partitions_once() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(3, (99, 499), SEED);
+        let mut index: CuckooIndex<TestPartition> = CuckooIndex::new(8);
+        tests::fill_index(&mut index, partitions);
+        // the fingerprint of the key in every slot of both of its buckets
+        let key = tests::create_partition_data(&partitions[1]).next().unwrap();
+        let fingerprint: u16 = index.scheme().fingerprint(key);
+        for bucket in &mut index.buckets {
+            bucket.fill(fingerprint);
+        }
+        assert_eq!(index.query(key)?, partitions.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn query_many_values() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let mut index: CuckooIndex<TestPartition> = CuckooIndex::new(80);
+        tests::fill_index(&mut index, partitions);
+        let values: Vec<u64> = partitions
+            .iter()
+            .flat_map(|p| tests::create_partition_data(p).take(20))
+            .chain(0..100)
+            .collect();
+        let expected: Vec<_> = values
+            .iter()
+            .map(|value| index.query_ids(*value))
+            .collect::<anyhow::Result<_>>()?;
+        assert_eq!(index.query_many_ids(&values)?, expected);
+        Ok(())
+    }
+
     #[test]
     fn dont_yield_removed_partitions() -> anyhow::Result<()> {
         let partitions = &tests::create_test_data(10, (99, 499), SEED);
         let mut index: CuckooIndex<TestPartition> = CuckooIndex::new(80);
-        tests::fill_index(&mut index, &partitions);
-        index.remove(&partitions[3]);
+        tests::fill_index(&mut index, partitions);
+        index.remove(&partitions[3])?;
         if let Some(first_val) = tests::create_partition_data(&partitions[3]).next() {
             assert!(
                 !index.query(first_val)?.contains(&partitions[3]),
@@ -197,4 +334,42 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn query_stashed_values() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(20, (99, 499), SEED);
+        // few buckets, so some partitions end up with stashed fingerprints
+        let mut index: CuckooIndex<TestPartition> = CuckooIndex::new(8);
+        tests::fill_index(&mut index, partitions);
+        assert!(index.partitions.iter().any(|p| !p.stash.is_empty()));
+
+        for p in partitions {
+            let values: Vec<u64> = tests::create_partition_data(p).collect();
+            for (value, result) in values.iter().zip(index.query_many(&values)?) {
+                assert!(index.query(*value)?.contains(p));
+                assert!(result.contains(p));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn seeded_layout_is_reproducible() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let mut one_by_one: CuckooIndex<TestPartition> = CuckooIndex::new(80).with_seed(7);
+        tests::fill_index(&mut one_by_one, partitions);
+        let mut batched: CuckooIndex<TestPartition> = CuckooIndex::new(80).with_seed(7);
+        batched.add_many(
+            partitions
+                .iter()
+                .map(|p| (p.clone(), tests::create_partition_data(p)))
+                .collect(),
+        )?;
+        assert_eq!(one_by_one.buckets, batched.buckets);
+
+        let mut reseeded: CuckooIndex<TestPartition> = CuckooIndex::new(80).with_seed(8);
+        tests::fill_index(&mut reseeded, partitions);
+        assert_ne!(one_by_one.buckets, reseeded.buckets);
+        Ok(())
+    }
 }
@@ -0,0 +1,521 @@
+use siphasher::sip::SipHasher13;
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+// Index keys are SipHash-1-3 hashes with fixed keys over a type tag followed by a
+// canonical encoding of the value. Both are part of the persisted format: changing
+// them changes which partitions existing indexes yield for a value.
+//
+// Values that compare equal hash to the same key across types of the same kind:
+// - integers (tag 1): the value as i128, so `5u8`, `5i32` and `5i64` agree
+// - floats (tag 2): the value as f64, with -0.0 mapped to 0.0 and a single NaN
+// - strings (tag 3): the UTF-8 bytes
+// - binary (tag 4): the bytes
+// - booleans (tag 5): a single byte, 0 or 1
+// - dates (tag 6): days since the unix epoch as i32
+// - decimals (tag 7): the unscaled value as i128 and the scale as u8, with trailing
+//   zeros removed, so 1.10 and 1.1 agree
+// Different kinds never agree, e.g. the integer 5 and the float 5.0 hash differently.
+// Values of named columns sharing a filter are keyed by `column_key` (tag 8): the key
+// of the value as u64 followed by the UTF-8 bytes of the column name.
+// All integers are encoded little-endian.
+const KEY0: u64 = 0x7061_7274_6974_696f;
+const KEY1: u64 = 0x6e2d_696e_6465_7821;
+
+const TAG_INT: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_BINARY: u8 = 4;
+const TAG_BOOL: u8 = 5;
+const TAG_DATE: u8 = 6;
+const TAG_DECIMAL: u8 = 7;
+const TAG_COLUMN: u8 = 8;
+
+/// A value that can be stored in an index. The index only stores the value's key,
+/// callers indexing and querying values of the same column must agree on the key,
+/// which is guaranteed by hashing both through this trait.
+pub trait Hashable {
+    /// The stable key of the value, encoded as described at the top of this module.
+    fn index_key(&self) -> u64;
+}
+
+fn hash(tag: u8, bytes: &[u8]) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(KEY0, KEY1);
+    hasher.write_u8(tag);
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// The key of a value with key `key` in the column `column`, for storing the values of
+/// several columns in the same filter: equal values of different columns get
+/// different keys, so querying one column doesn't yield matches in the others.
+pub fn column_key(column: &str, key: u64) -> u64 {
+    let mut bytes = Vec::with_capacity(8 + column.len());
+    bytes.extend_from_slice(&key.to_le_bytes());
+    bytes.extend_from_slice(column.as_bytes());
+    hash(TAG_COLUMN, &bytes)
+}
+
+/// A date, as the number of days since 1970-01-01.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub struct Date(pub i32);
+
+/// A decimal number `value * 10^-scale`. Decimals are equal if they represent the same
+/// number, regardless of their scale, e.g. 1.10 and 1.1.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Decimal {
+    pub value: i128,
+    pub scale: u8,
+}
+
+impl Decimal {
+    /// The value and scale with trailing zeros removed.
+    fn normalized(&self) -> (i128, u8) {
+        let (mut value, mut scale) = (self.value, self.scale);
+        while scale > 0 && value % 10 == 0 {
+            value /= 10;
+            scale -= 1;
+        }
+        (value, scale)
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.normalized() == other.normalized()
+    }
+}
+
+impl Eq for Decimal {}
+
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.normalized().hash(state);
+    }
+}
+
+impl<T: Hashable + ?Sized> Hashable for &T {
+    fn index_key(&self) -> u64 {
+        (**self).index_key()
+    }
+}
+
+macro_rules! hashable_int {
+    ($($t:ty),*) => {
+        $(impl Hashable for $t {
+            fn index_key(&self) -> u64 {
+                hash(TAG_INT, &(*self as i128).to_le_bytes())
+            }
+        })*
+    };
+}
+
+hashable_int!(i8, i16, i32, i64, u8, u16, u32, u64);
+
+impl Hashable for f64 {
+    fn index_key(&self) -> u64 {
+        let canonical = if self.is_nan() {
+            f64::NAN
+        } else if *self == 0.0 {
+            0.0
+        } else {
+            *self
+        };
+        hash(TAG_FLOAT, &canonical.to_bits().to_le_bytes())
+    }
+}
+
+impl Hashable for f32 {
+    fn index_key(&self) -> u64 {
+        // every f32 is exactly representable as f64
+        f64::from(*self).index_key()
+    }
+}
+
+impl Hashable for str {
+    fn index_key(&self) -> u64 {
+        hash(TAG_STRING, self.as_bytes())
+    }
+}
+
+impl Hashable for String {
+    fn index_key(&self) -> u64 {
+        self.as_str().index_key()
+    }
+}
+
+impl Hashable for [u8] {
+    fn index_key(&self) -> u64 {
+        hash(TAG_BINARY, self)
+    }
+}
+
+impl Hashable for Vec<u8> {
+    fn index_key(&self) -> u64 {
+        self.as_slice().index_key()
+    }
+}
+
+impl Hashable for bool {
+    fn index_key(&self) -> u64 {
+        hash(TAG_BOOL, &[*self as u8])
+    }
+}
+
+impl Hashable for Date {
+    fn index_key(&self) -> u64 {
+        hash(TAG_DATE, &self.0.to_le_bytes())
+    }
+}
+
+impl Hashable for Decimal {
+    fn index_key(&self) -> u64 {
+        let (value, scale) = self.normalized();
+        let mut bytes = [0u8; 17];
+        bytes[..16].copy_from_slice(&value.to_le_bytes());
+        bytes[16] = scale;
+        hash(TAG_DECIMAL, &bytes)
+    }
+}
+
+impl PartialOrd for Decimal {
+    /// Compares the numbers the decimals represent, `None` if rescaling them to the
+    /// same scale overflows.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let rescale = |decimal: &Decimal, scale: u8| {
+            10i128
+                .checked_pow(u32::from(scale - decimal.scale))
+                .and_then(|factor| decimal.value.checked_mul(factor))
+        };
+        let scale = self.scale.max(other.scale);
+        Some(rescale(self, scale)?.cmp(&rescale(other, scale)?))
+    }
+}
+
+/// A typed value of any of the supported kinds, e.g. parsed from text or read from
+/// Parquet statistics.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Value {
+    Int(i128),
+    Float(f64),
+    String(String),
+    Binary(Vec<u8>),
+    Bool(bool),
+    Date(Date),
+    Decimal(Decimal),
+}
+
+impl Hashable for Value {
+    fn index_key(&self) -> u64 {
+        match self {
+            Value::Int(value) => hash(TAG_INT, &value.to_le_bytes()),
+            Value::Float(value) => value.index_key(),
+            Value::String(value) => value.index_key(),
+            Value::Binary(value) => value.index_key(),
+            Value::Bool(value) => value.index_key(),
+            Value::Date(value) => value.index_key(),
+            Value::Decimal(value) => value.index_key(),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    /// Only values of the same kind are ordered.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::String(a), Value::String(b)) => a.partial_cmp(b),
+            (Value::Binary(a), Value::Binary(b)) => a.partial_cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+            (Value::Date(a), Value::Date(b)) => a.partial_cmp(b),
+            (Value::Decimal(a), Value::Decimal(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of a value, determining how it's hashed. Used to hash values given as text,
+/// e.g. on the command line, the same way as the typed values they represent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueType {
+    Int,
+    Float,
+    String,
+    /// Binary values, given as hex digits.
+    Binary,
+    Bool,
+    /// Dates, given as `YYYY-MM-DD`.
+    Date,
+    Decimal,
+}
+
+impl ValueType {
+    /// Parse `value` as a value of this type.
+    pub fn parse(self, value: &str) -> anyhow::Result<Value> {
+        Ok(match self {
+            ValueType::Int => Value::Int(value.parse()?),
+            ValueType::Float => Value::Float(value.parse()?),
+            ValueType::String => Value::String(value.to_string()),
+            ValueType::Binary => Value::Binary(parse_hex(value)?),
+            ValueType::Bool => Value::Bool(value.parse()?),
+            ValueType::Date => Value::Date(parse_date(value)?),
+            ValueType::Decimal => Value::Decimal(parse_decimal(value)?),
+        })
+    }
+
+    /// Parse `value` as a value of this type and return its key.
+    pub fn parse_key(self, value: &str) -> anyhow::Result<u64> {
+        Ok(self.parse(value)?.index_key())
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "int" | "i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" | "u64" => ValueType::Int,
+            "float" | "f32" | "f64" => ValueType::Float,
+            "string" => ValueType::String,
+            "binary" => ValueType::Binary,
+            "bool" => ValueType::Bool,
+            "date" => ValueType::Date,
+            "decimal" => ValueType::Decimal,
+            _ => anyhow::bail!("unknown value type '{}'", s),
+        })
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ValueType::Int => "int",
+            ValueType::Float => "float",
+            ValueType::String => "string",
+            ValueType::Binary => "binary",
+            ValueType::Bool => "bool",
+            ValueType::Date => "date",
+            ValueType::Decimal => "decimal",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn parse_hex(value: &str) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        value.bytes().all(|b| b.is_ascii_hexdigit()),
+        "invalid hex digits in '{}'",
+        value
+    );
+    anyhow::ensure!(
+        value.len().is_multiple_of(2),
+        "odd number of hex digits in '{}'",
+        value
+    );
+    (0..value.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&value[i..i + 2], 16)?))
+        .collect()
+}
+
+fn parse_date(value: &str) -> anyhow::Result<Date> {
+    let parts: Vec<_> = value.split('-').collect();
+    anyhow::ensure!(
+        parts.len() == 3,
+        "expected a date as YYYY-MM-DD, got '{}'",
+        value
+    );
+    let (year, month, day): (i64, i64, i64) =
+        (parts[0].parse()?, parts[1].parse()?, parts[2].parse()?);
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    anyhow::ensure!(
+        (1..=12).contains(&month) && (1..=days_in_month).contains(&day),
+        "invalid date '{}'",
+        value
+    );
+    // days since the epoch in the proleptic Gregorian calendar, with years starting
+    // in March so the leap day is the last day of a year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Ok(Date(i32::try_from(era * 146097 + day_of_era - 719468)?))
+}
+
+fn parse_decimal(value: &str) -> anyhow::Result<Decimal> {
+    let (integer, fraction) = value.split_once('.').unwrap_or((value, ""));
+    anyhow::ensure!(
+        fraction.chars().all(|c| c.is_ascii_digit()),
+        "invalid decimal '{}'",
+        value
+    );
+    Ok(Decimal {
+        value: format!("{}{}", integer, fraction).parse()?,
+        scale: u8::try_from(fraction.len())?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{column_key, Date, Decimal, Hashable, Value, ValueType};
+    use crate::index::{
+        in_memory::CuckooIndex, tests::TestPartition, PartitionFilter, PartitionIndex,
+    };
+
+    #[test]
+    fn stable_keys() {
+        // guards against accidentally changing the keys of persisted indexes
+        assert_eq!(42i64.index_key(), 0x867c_ddb6_1e95_ff11);
+        assert_eq!("partition".index_key(), 0xe661_9890_00c6_f9e8);
+        assert_eq!(column_key("id", 42i64.index_key()), 0x7ea9_8528_62f1_cd7a);
+    }
+
+    #[test]
+    fn equal_values_agree_across_types() {
+        assert_eq!(5u8.index_key(), 5i64.index_key());
+        assert_eq!((-5i32).index_key(), (-5i64).index_key());
+        // widening doesn't wrap around
+        assert_ne!(u64::MAX.index_key(), (-1i64).index_key());
+        assert_eq!(1.5f32.index_key(), 1.5f64.index_key());
+        assert_eq!((-0.0f64).index_key(), 0.0f64.index_key());
+        assert_eq!(f64::NAN.index_key(), (-f64::NAN).index_key());
+        assert_eq!("abc".index_key(), String::from("abc").index_key());
+        assert_eq!(b"abc"[..].index_key(), b"abc".to_vec().index_key());
+        assert_eq!(
+            Decimal {
+                value: 110,
+                scale: 2
+            }
+            .index_key(),
+            Decimal {
+                value: 11,
+                scale: 1
+            }
+            .index_key()
+        );
+    }
+
+    #[test]
+    fn columns_differ() {
+        let key = 42i64.index_key();
+        assert_eq!(column_key("a", key), column_key("a", 42i32.index_key()));
+        assert_ne!(column_key("a", key), column_key("b", key));
+        assert_ne!(column_key("a", key), key);
+        assert_ne!(column_key("a", key), column_key("ab", key));
+    }
+
+    #[test]
+    fn different_kinds_differ() {
+        assert_ne!(5i64.index_key(), 5.0f64.index_key());
+        assert_ne!("abc".index_key(), b"abc"[..].index_key());
+        assert_ne!(1i32.index_key(), true.index_key());
+        assert_ne!(Date(5).index_key(), 5i32.index_key());
+        assert_ne!(Decimal { value: 5, scale: 0 }.index_key(), 5i64.index_key());
+        assert_ne!(
+            Decimal {
+                value: 11,
+                scale: 1
+            }
+            .index_key(),
+            Decimal {
+                value: 11,
+                scale: 2
+            }
+            .index_key()
+        );
+    }
+
+    #[test]
+    fn parse_values() -> anyhow::Result<()> {
+        let parse = |value_type: &str, value| value_type.parse::<ValueType>()?.parse_key(value);
+        assert_eq!(parse("i32", "-42")?, (-42i32).index_key());
+        assert_eq!(parse("u64", "18446744073709551615")?, u64::MAX.index_key());
+        assert_eq!(parse("f64", "1.5")?, 1.5f32.index_key());
+        assert_eq!(parse("string", "abc")?, "abc".index_key());
+        assert_eq!(parse("binary", "00ff")?, [0u8, 255][..].index_key());
+        assert_eq!(parse("bool", "true")?, true.index_key());
+        assert_eq!(parse("date", "1970-01-01")?, Date(0).index_key());
+        assert_eq!(parse("date", "2000-03-01")?, Date(11017).index_key());
+        assert_eq!(parse("date", "1969-12-31")?, Date(-1).index_key());
+        assert_eq!(
+            parse("decimal", "-12.30")?,
+            Decimal {
+                value: -123,
+                scale: 1
+            }
+            .index_key()
+        );
+        assert!(parse("int", "abc").is_err());
+        assert!(parse("binary", "€a").is_err());
+        assert!(parse("date", "2000-13-01").is_err());
+        assert!(parse("date", "2000-02-31").is_err());
+        assert!(parse("date", "2001-04-31").is_err());
+        assert!(parse("date", "1900-02-29").is_err());
+        assert_eq!(parse("date", "2000-02-29")?, Date(11016).index_key());
+        assert!("i128".parse::<ValueType>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compare_values() {
+        let decimal = |value, scale| Value::Decimal(Decimal { value, scale });
+        assert!(Value::Int(-5) < Value::Int(3));
+        assert!(Value::String("abc".into()) < Value::String("abd".into()));
+        assert!(Value::Date(Date(-1)) < Value::Date(Date(0)));
+        assert!(decimal(15, 1) > decimal(149, 2));
+        assert_eq!(
+            decimal(110, 2).partial_cmp(&decimal(11, 1)),
+            Some(std::cmp::Ordering::Equal)
+        );
+        // equality agrees with the ordering and the key
+        assert_eq!(decimal(110, 2), decimal(11, 1));
+        assert_ne!(decimal(11, 2), decimal(11, 1));
+        let set: std::collections::HashSet<_> = [
+            Decimal {
+                value: 110,
+                scale: 2,
+            },
+            Decimal {
+                value: 11,
+                scale: 1,
+            },
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(set.len(), 1);
+        // rescaling overflows
+        assert_eq!(decimal(i128::MAX, 0).partial_cmp(&decimal(1, 1)), None);
+        assert_eq!(Value::Int(5).partial_cmp(&Value::Float(5.0)), None);
+        assert_eq!(Value::Float(f64::NAN).partial_cmp(&Value::Float(0.0)), None);
+        assert_eq!(Value::Int(42).index_key(), 42i64.index_key());
+    }
+
+    #[test]
+    fn query_typed_values() -> anyhow::Result<()> {
+        let partition = TestPartition {
+            id: 0,
+            size: 3,
+            seed: 0,
+        };
+        let mut index: CuckooIndex<TestPartition> = CuckooIndex::new(8);
+        index.add_values(["a", "b", "c"].into_iter(), partition.clone());
+        assert_eq!(index.query_value("b")?, vec![partition.clone()]);
+        assert_eq!(index.query_value(&String::from("c"))?, vec![partition]);
+        Ok(())
+    }
+}
@@ -1,44 +1,124 @@
+//! The underlying assumption here is that we're indexing "partitions"
+//! on an unknown stream of data. The only representation we can retrieve
+//! is a hashed representation of all values to be indexed.
+//! This allows some flexibility down the road by allowing different types
+//! of partitions (parquet files, row groups, hive partitions, ...) or even
+//! heterogeneous data that has different shape or form.
+//! It is also possible to index multiple columns into the same index,
+//! even if the columns have different types.
+//!
+//! TODO:
+//! - how do we actually "reference" a partition that we identified?
+//!   - is there some sort of serializable representation we can store?
+//!   - do we just return an (increasing) number (our index / offset)?
+//!     - this would enable us to solve the problem elsewhere
+//! - can we support indexing multiple columns?
+//!   - it feels wasteful to register a single partition multiple times
+//!   - metadata overlaps, and using the same offset in all indexes would be great
+//! - we could think of the partition itself as just some identifier of the aactual
+//!   partition entity, and do `add(values: Iterator<Item = u64>, i: ID); in the Index
+//!   - I like that: we decouple the knowledge about structure, type, ... of partitions
+//!   - we pass in the minimum necessary thing to do its job.
+//! - in terms of API design, can we split the API into multiple traits?
+//!   - `PartitionLookup`
+//!   - `PartitionIndexer`
+//!   - ... (delete) ...
+//!   - It may make sense to have higher-level constructs that manage mutations
+//!     - by maintaining a WAL + batching writes in memory
+//!     - tombstone handling
+//!     - they could even manage multiple Lookup sets
+//!     - e.g. a partition key (as in, hive partitioning) to split into multiple indexes
+
+mod batch;
 pub mod in_memory;
+pub mod key;
+pub mod multi_column;
 pub mod poc;
+pub mod predicate;
+
+use self::key::Hashable;
 
-/// The underlying assumption here is that we're indexing "partitions"
-/// on an unknown stream of data. The only representation we can retrieve
-/// is a hashed representation of all values to be indexed.
-/// This allows some flexibility down the road by allowing different types
-/// of partitions (parquet files, row groups, hive partitions, ...) or even
-/// heterogeneous data that has different shape or form.
-/// It is also possible to index multiple columns into the same index,
-/// even if the columns have different types.
-
-/// TODO:
-/// - how do we actually "reference" a partition that we identified?
-///   - is there some sort of serializable representation we can store?
-///   - do we just return an (increasing) number (our index / offset)?
-///     - this would enable us to solve the problem elsewhere
-/// - can we support indexing multiple columns?
-///   - it feels wasteful to register a single partition multiple times
-///   - metadata overlaps, and using the same offset in all indexes would be great
-/// - we could think of the partition itself as just some identifier of the aactual
-///   partition entity, and do `add(values: Iterator<Item = u64>, i: ID); in the Index
-///   - I like that: we decouple the knowledge about structure, type, ... of partitions
-///   - we pass in the minimum necessary thing to do its job.
-/// - in terms of API design, can we split the API into multiple traits?
-///   - `PartitionLookup`
-///   - `PartitionIndexer`
-///   - ... (delete) ...
-///   - It may make sense to have higher-level constructs that manage mutations
-///     - by maintaining a WAL + batching writes in memory
-///     - tombstone handling
-///     - they could even manage multiple Lookup sets
-///     - e.g. a partition key (as in, hive partitioning) to split into multiple indexes
+/// Identifies a partition by its position among all partitions of an index,
+/// in the order they were added.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub struct PartitionId(pub usize);
+
+/// A trait that resolves partition IDs to the partitions they identify.
+pub trait PartitionLookup<P> {
+    /// The partition with the given ID, `None` if the index doesn't know the ID.
+    fn partition(&self, id: PartitionId) -> Option<&P>;
+}
 
 /// A trait that allows querying a data set for matching partitions.
 /// TODO: P should probably be somehow serializable
-pub trait PartitionFilter<P> {
+pub trait PartitionFilter<P>: PartitionLookup<P> {
+    /// Query the IDs of matching partitions for a given value.
+    /// Each matching partition is returned once, in the order of their IDs.
+    fn query_ids(&self, value: u64) -> anyhow::Result<Vec<PartitionId>>;
+
     /// Query matching partitions for a given value
-    /// TODO: what's the result type here? It's either the partition, or
-    /// some kind of partition ID.
-    fn query(&self, value: u64) -> anyhow::Result<Vec<P>>;
+    ///
+    /// Default implementation resolves the result of `Self::query_ids`.
+    fn query(&self, value: u64) -> anyhow::Result<Vec<P>>
+    where
+        P: Clone,
+    {
+        Ok(self
+            .query_ids(value)?
+            .into_iter()
+            .map(|id| {
+                self.partition(id)
+                    .expect("query yielded unknown partition")
+                    .clone()
+            })
+            .collect())
+    }
+
+    /// Query matching partitions for a typed value, see [`Hashable`].
+    fn query_value(&self, value: &(impl Hashable + ?Sized)) -> anyhow::Result<Vec<P>>
+    where
+        P: Clone,
+    {
+        self.query(value.index_key())
+    }
+
+    /// Query the IDs of matching partitions for many values at once, returning the
+    /// result of `Self::query_ids` for each value.
+    ///
+    /// Default implementation sequentially calls `Self::query_ids` one value at a time.
+    fn query_many_ids(&self, values: &[u64]) -> anyhow::Result<Vec<Vec<PartitionId>>>
+    where
+        P: Send + Sync,
+        Self: Sync,
+    {
+        values.iter().map(|value| self.query_ids(*value)).collect()
+    }
+
+    /// Query matching partitions for many values at once, returning the result of
+    /// `Self::query` for each value.
+    ///
+    /// Default implementation resolves the result of `Self::query_many_ids`.
+    fn query_many(&self, values: &[u64]) -> anyhow::Result<Vec<Vec<P>>>
+    where
+        P: Clone + Send + Sync,
+        Self: Sync,
+    {
+        Ok(self
+            .query_many_ids(values)?
+            .into_iter()
+            .map(|ids| {
+                ids.into_iter()
+                    .map(|id| {
+                        self.partition(id)
+                            .expect("query yielded unknown partition")
+                            .clone()
+                    })
+                    .collect()
+            })
+            .collect())
+    }
 }
 
 pub trait PartitionIndex<P> {
@@ -47,6 +127,12 @@ pub trait PartitionIndex<P> {
     /// @param partition the partition identifier to associate the values with
     fn add(&mut self, values: impl Iterator<Item = u64>, partition: P);
 
+    /// Add a partition with typed values, hashed consistently with `query_value`.
+    /// See [`Hashable`].
+    fn add_values<V: Hashable>(&mut self, values: impl Iterator<Item = V>, partition: P) {
+        self.add(values.map(|value| value.index_key()), partition);
+    }
+
     /// Add a batch of multiple partitions to the index.
     /// @param partitions the partitions, a tuple representing the partition identifier and values
     ///
@@ -64,7 +150,18 @@ pub trait PartitionIndex<P> {
 
     /// Remove a partition from the index.
     /// @param partition to remove
-    fn remove(&mut self, partition: &P);
+    fn remove(&mut self, partition: &P) -> anyhow::Result<()>;
+
+    /// Remove multiple partitions from the index.
+    /// @param partitions to remove
+    ///
+    /// Default implementation sequentially calls `Self::remove` one partition at a time.
+    fn remove_many(&mut self, partitions: &[P]) -> anyhow::Result<()> {
+        for partition in partitions {
+            self.remove(partition)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -83,7 +180,7 @@ pub mod tests {
 
     pub fn fill_index(index: &mut impl PartitionIndex<TestPartition>, ps: &[TestPartition]) {
         for partition in ps {
-            index.add(create_partition_data(&partition), partition.clone());
+            index.add(create_partition_data(partition), partition.clone());
         }
     }
 
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+
+use super::{
+    in_memory::{CuckooIndex, PartitionInfo},
+    PartitionFilter, PartitionId, PartitionIndex, PartitionLookup,
+};
+
+/// An in-memory index over several named columns. Each column has its own buckets,
+/// but partitions are registered once for all columns and share their `PartitionId`.
+///
+/// It is never persisted. Indexes of Parquet files store all columns in a single
+/// [`PersistentIndex`](super::poc::PersistentIndex) instead, keying each value by its
+/// column, see [`column_key`](super::key::column_key).
+#[derive(Debug)]
+pub struct MultiColumnIndex<P> {
+    num_buckets: u64,
+    partitions: Vec<SharedPartition<P>>,
+    // the partitions of each column are in the same order as `partitions`,
+    // their `active` flags are unused
+    columns: BTreeMap<String, CuckooIndex<()>>,
+}
+
+#[derive(Debug)]
+struct SharedPartition<P> {
+    partition: P,
+    active: bool,
+}
+
+impl<P> MultiColumnIndex<P> {
+    pub fn new(buckets: u64) -> Self {
+        Self {
+            num_buckets: buckets,
+            partitions: vec![],
+            columns: BTreeMap::new(),
+        }
+    }
+
+    /// Add a partition with the values of each of its columns. Columns that haven't
+    /// been seen before are added to the index, columns the partition has no values
+    /// for don't take any slots. The values of a column given more than once are
+    /// merged.
+    pub fn add<S, I>(
+        &mut self,
+        columns: impl IntoIterator<Item = (S, I)>,
+        partition: P,
+    ) -> PartitionId
+    where
+        S: Into<String>,
+        I: Iterator<Item = u64>,
+    {
+        let id = PartitionId(self.partitions.len());
+        let mut grouped: BTreeMap<String, Vec<I>> = BTreeMap::new();
+        for (name, values) in columns {
+            grouped.entry(name.into()).or_default().push(values);
+        }
+        for (name, values) in grouped {
+            let num_buckets = self.num_buckets;
+            let column = self.columns.entry(name).or_insert_with(|| {
+                let mut column = CuckooIndex::new(num_buckets);
+                for _ in 0..id.0 {
+                    column.partitions.push(empty_partition());
+                }
+                column
+            });
+            column.add(values.into_iter().flatten(), ());
+        }
+        for column in self.columns.values_mut() {
+            if column.partitions.len() == id.0 {
+                column.partitions.push(empty_partition());
+            }
+        }
+        self.partitions.push(SharedPartition {
+            partition,
+            active: true,
+        });
+        id
+    }
+
+    /// The names of all indexed columns, in ascending order.
+    pub fn columns(&self) -> impl Iterator<Item = &str> {
+        self.columns.keys().map(String::as_str)
+    }
+
+    /// A filter over a single column, `None` if the column isn't indexed.
+    pub fn column(&self, name: &str) -> Option<Column<'_, P>> {
+        self.columns.get(name).map(|filter| Column {
+            partitions: &self.partitions,
+            filter,
+        })
+    }
+
+    /// Query the IDs of the partitions that may contain `key` in the given column.
+    pub fn query_ids(&self, column: &str, key: u64) -> anyhow::Result<Vec<PartitionId>> {
+        self.try_column(column)?.query_ids(key)
+    }
+
+    /// Query the partitions that may contain `key` in the given column.
+    pub fn query(&self, column: &str, key: u64) -> anyhow::Result<Vec<P>>
+    where
+        P: Clone,
+    {
+        self.try_column(column)?.query(key)
+    }
+
+    /// Remove a partition from all columns at once.
+    pub fn remove(&mut self, to_be_removed: &P) -> anyhow::Result<()>
+    where
+        P: PartialEq,
+    {
+        for p in self.partitions.iter_mut() {
+            if &p.partition == to_be_removed {
+                p.active = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn try_column(&self, name: &str) -> anyhow::Result<Column<'_, P>> {
+        self.column(name)
+            .ok_or_else(|| anyhow::anyhow!("column '{}' is not indexed", name))
+    }
+}
+
+impl<P> PartitionLookup<P> for MultiColumnIndex<P> {
+    fn partition(&self, id: PartitionId) -> Option<&P> {
+        self.partitions.get(id.0).map(|p| &p.partition)
+    }
+}
+
+fn empty_partition() -> PartitionInfo<()> {
+    PartitionInfo {
+        partition: (),
+        bucket_size: 0,
+        active: true,
+        elements: 0,
+        stash: vec![],
+    }
+}
+
+/// A single column of a [`MultiColumnIndex`].
+pub struct Column<'a, P> {
+    partitions: &'a [SharedPartition<P>],
+    filter: &'a CuckooIndex<()>,
+}
+
+impl<P> PartitionLookup<P> for Column<'_, P> {
+    fn partition(&self, id: PartitionId) -> Option<&P> {
+        self.partitions.get(id.0).map(|p| &p.partition)
+    }
+}
+
+impl<P> PartitionFilter<P> for Column<'_, P> {
+    fn query_ids(&self, value: u64) -> anyhow::Result<Vec<PartitionId>> {
+        let mut ids = self.filter.query_ids(value)?;
+        ids.retain(|id| self.partitions[id.0].active);
+        Ok(ids)
+    }
+
+    fn query_many_ids(&self, values: &[u64]) -> anyhow::Result<Vec<Vec<PartitionId>>>
+    where
+        P: Send + Sync,
+        Self: Sync,
+    {
+        let mut results = self.filter.query_many_ids(values)?;
+        for ids in &mut results {
+            ids.retain(|id| self.partitions[id.0].active);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MultiColumnIndex;
+    use crate::index::{
+        tests::{self, TestPartition},
+        PartitionFilter, PartitionId, PartitionLookup,
+    };
+
+    static SEED: u64 = 1337;
+
+    fn create_index(partitions: &[TestPartition]) -> MultiColumnIndex<TestPartition> {
+        let mut index = MultiColumnIndex::new(80);
+        for p in partitions {
+            // column b holds the same values shifted by one
+            let columns = vec![
+                ("a", tests::create_partition_data(p).collect::<Vec<_>>()),
+                (
+                    "b",
+                    tests::create_partition_data(p)
+                        .map(|v| v.wrapping_add(1))
+                        .collect(),
+                ),
+            ];
+            let id = index.add(
+                columns
+                    .into_iter()
+                    .map(|(name, values)| (name, values.into_iter())),
+                p.clone(),
+            );
+            assert_eq!(id, PartitionId(p.id));
+        }
+        index
+    }
+
+    #[test]
+    fn query_columns() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let index = create_index(partitions);
+        assert_eq!(index.columns().collect::<Vec<_>>(), vec!["a", "b"]);
+        for p in partitions {
+            let first_val = tests::create_partition_data(p).next().unwrap();
+            assert!(index.query("a", first_val)?.contains(p));
+            assert!(index.query("b", first_val.wrapping_add(1))?.contains(p));
+            assert_eq!(index.partition(PartitionId(p.id)), Some(p));
+        }
+        assert!(index.query("c", 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn add_columns_later() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(4, (99, 499), SEED);
+        let mut index = MultiColumnIndex::new(80);
+        index.add(
+            [("a", tests::create_partition_data(&partitions[0]))],
+            partitions[0].clone(),
+        );
+        index.add(
+            [("b", tests::create_partition_data(&partitions[1]))],
+            partitions[1].clone(),
+        );
+        index.add(
+            [
+                ("a", tests::create_partition_data(&partitions[2])),
+                ("b", tests::create_partition_data(&partitions[2])),
+            ],
+            partitions[2].clone(),
+        );
+        let column_a = index.column("a").unwrap();
+        let column_b = index.column("b").unwrap();
+        for (p, in_a, in_b) in [(0, true, false), (1, false, true), (2, true, true)] {
+            let first_val = tests::create_partition_data(&partitions[p]).next().unwrap();
+            assert_eq!(
+                column_a.query_ids(first_val)?.contains(&PartitionId(p)),
+                in_a
+            );
+            assert_eq!(
+                column_b.query_ids(first_val)?.contains(&PartitionId(p)),
+                in_b
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn merge_repeated_columns() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(2, (99, 499), SEED);
+        let mut index = MultiColumnIndex::new(80);
+        index.add(
+            [
+                ("a", tests::create_partition_data(&partitions[0])),
+                ("a", tests::create_partition_data(&partitions[1])),
+            ],
+            partitions[0].clone(),
+        );
+        let column = index.column("a").unwrap();
+        for p in partitions {
+            for value in tests::create_partition_data(p) {
+                assert!(column.query_ids(value)?.contains(&PartitionId(0)));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn remove_from_all_columns() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let mut index = create_index(partitions);
+        index.remove(&partitions[3])?;
+        let first_val = tests::create_partition_data(&partitions[3]).next().unwrap();
+        assert!(!index.query("a", first_val)?.contains(&partitions[3]));
+        assert!(!index
+            .query("b", first_val.wrapping_add(1))?
+            .contains(&partitions[3]));
+        let column = index.column("a").unwrap();
+        assert!(!column.query_many_ids(&[first_val])?[0].contains(&PartitionId(3)));
+        Ok(())
+    }
+}
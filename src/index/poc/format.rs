@@ -0,0 +1,476 @@
+use std::{fmt, ops::Range};
+
+use crate::filter::cuckoo::{BucketCount, BucketReduction, HashScheme};
+
+// Every file of a persisted index starts with a fixed-size header:
+// - 8 bytes magic, identifying the kind of file
+// - format version (u16)
+// - fingerprint width in bits (u8)
+// - identifiers of the fingerprint and the bucket hash function (u8 each)
+// - identifier of the reduction of bucket hashes to buckets (u8, since version 3)
+// - 2 reserved bytes, always zero
+// - number of buckets (u64)
+// All integers are little-endian, which also applies to everything following the header:
+// `partitions.data` uses bincode's default encoding (little-endian, fixed-size integers),
+// segments store their offset table and fingerprints as little-endian integers.
+// Since version 3, the partitions in `partitions.data` include their stash and
+// `partitions.data` ends with the seed of the index's evictions.
+// Everything following the header is protected by CRC32 checksums: `partitions.data` ends
+// with the checksum of its payload, segments store one checksum per bucket (see `segment`).
+pub(crate) const HEADER_LEN: usize = 24;
+
+pub(crate) const MANIFEST_MAGIC: [u8; 8] = *b"PIDXMETA";
+pub(crate) const SEGMENT_MAGIC: [u8; 8] = *b"PIDXSEGM";
+
+pub const FORMAT_VERSION: u16 = 3;
+/// the oldest version that can still be read: version 2 only knows 16-bit fingerprints
+/// hashed with the legacy scheme and reduced modulo the number of buckets, its
+/// `partitions.data` lacks the stashes and the seed, which was always the default one
+pub const MIN_FORMAT_VERSION: u16 = 2;
+/// the fingerprint width of indexes that don't choose one
+pub const FINGERPRINT_BITS: u8 = 16;
+/// fingerprints are stored in the smallest unsigned integer holding all of their bits
+pub const SUPPORTED_FINGERPRINT_BITS: [u8; 4] = [8, 12, 16, 32];
+/// keyed SipHash-1-3, re-hashed until the lower fingerprint bits are not zero
+pub const FINGERPRINT_HASH_SIPHASH13: u8 = 1;
+/// SipHash-1-3 with zero keys
+pub const BUCKET_HASH_SIPHASH13: u8 = 1;
+/// the first non-zero fingerprint bits of one half of a keyed 128-bit SipHash-1-3
+pub const FINGERPRINT_HASH_SIPHASH13_128: u8 = 2;
+/// the other half of the 128-bit SipHash-1-3
+pub const BUCKET_HASH_SIPHASH13_128: u8 = 2;
+/// the bucket hash modulo the number of buckets
+pub const BUCKET_REDUCTION_MODULO: u8 = 0;
+/// the lower bits of the bucket hash, the number of buckets is a power of two
+pub const BUCKET_REDUCTION_POWER_OF_TWO: u8 = 1;
+/// the upper 64 bits of the bucket hash times the number of buckets
+pub const BUCKET_REDUCTION_FAST_RANGE: u8 = 2;
+
+/// The header describing the on-disk format of a persisted index file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatHeader {
+    pub version: u16,
+    pub fingerprint_bits: u8,
+    pub fingerprint_hash: u8,
+    pub bucket_hash: u8,
+    pub bucket_reduction: u8,
+    pub num_buckets: u64,
+}
+
+impl FormatHeader {
+    /// The header for files written by this version of the index, using the default
+    /// fingerprint width and hash scheme.
+    pub fn current(num_buckets: u64) -> Self {
+        Self::new(num_buckets, FINGERPRINT_BITS, HashScheme::default())
+    }
+
+    /// The header for files written by this version of the index, storing fingerprints
+    /// of `fingerprint_bits` bits derived with `scheme` in `buckets`.
+    pub fn new(buckets: impl Into<BucketCount>, fingerprint_bits: u8, scheme: HashScheme) -> Self {
+        let buckets = buckets.into();
+        let (fingerprint_hash, bucket_hash) = match scheme {
+            HashScheme::Legacy => (FINGERPRINT_HASH_SIPHASH13, BUCKET_HASH_SIPHASH13),
+            HashScheme::Split128 => (FINGERPRINT_HASH_SIPHASH13_128, BUCKET_HASH_SIPHASH13_128),
+        };
+        Self {
+            version: FORMAT_VERSION,
+            fingerprint_bits,
+            fingerprint_hash,
+            bucket_hash,
+            bucket_reduction: match buckets.reduction() {
+                BucketReduction::Modulo => BUCKET_REDUCTION_MODULO,
+                BucketReduction::PowerOfTwo => BUCKET_REDUCTION_POWER_OF_TWO,
+                BucketReduction::FastRange => BUCKET_REDUCTION_FAST_RANGE,
+            },
+            num_buckets: buckets.count(),
+        }
+    }
+
+    /// The hash scheme the file was written with.
+    pub fn hash_scheme(&self) -> Result<HashScheme, FormatError> {
+        match (self.fingerprint_hash, self.bucket_hash) {
+            (FINGERPRINT_HASH_SIPHASH13, BUCKET_HASH_SIPHASH13) => Ok(HashScheme::Legacy),
+            (FINGERPRINT_HASH_SIPHASH13_128, BUCKET_HASH_SIPHASH13_128) => Ok(HashScheme::Split128),
+            (FINGERPRINT_HASH_SIPHASH13 | FINGERPRINT_HASH_SIPHASH13_128, bucket_hash) => {
+                Err(FormatError::UnknownBucketHash(bucket_hash))
+            }
+            (fingerprint_hash, _) => Err(FormatError::UnknownFingerprintHash(fingerprint_hash)),
+        }
+    }
+
+    /// The buckets of the index, along with the reduction of bucket hashes to them.
+    pub fn bucket_count(&self) -> Result<BucketCount, FormatError> {
+        let reduction = match self.bucket_reduction {
+            BUCKET_REDUCTION_MODULO => BucketReduction::Modulo,
+            BUCKET_REDUCTION_POWER_OF_TWO => BucketReduction::PowerOfTwo,
+            BUCKET_REDUCTION_FAST_RANGE => BucketReduction::FastRange,
+            id => return Err(FormatError::UnknownBucketReduction(id)),
+        };
+        BucketCount::try_new(self.num_buckets, reduction).ok_or(FormatError::InvalidBucketCount {
+            num_buckets: self.num_buckets,
+            bucket_reduction: self.bucket_reduction,
+        })
+    }
+
+    /// The number of bytes each persisted fingerprint takes.
+    pub fn fingerprint_size(&self) -> usize {
+        usize::from(self.fingerprint_bits).next_power_of_two() / 8
+    }
+
+    pub(crate) fn to_bytes(self, magic: [u8; 8]) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..8].copy_from_slice(&magic);
+        bytes[8..10].copy_from_slice(&self.version.to_le_bytes());
+        bytes[10] = self.fingerprint_bits;
+        bytes[11] = self.fingerprint_hash;
+        bytes[12] = self.bucket_hash;
+        bytes[13] = self.bucket_reduction;
+        bytes[16..24].copy_from_slice(&self.num_buckets.to_le_bytes());
+        bytes
+    }
+
+    /// Parse a header, rejecting files of a different kind or written in a format
+    /// this version of the index can't read.
+    pub(crate) fn from_bytes(
+        bytes: &[u8; HEADER_LEN],
+        magic: [u8; 8],
+    ) -> Result<Self, FormatError> {
+        if bytes[0..8] != magic {
+            return Err(FormatError::BadMagic {
+                expected: magic,
+                found: bytes[0..8].try_into().unwrap(),
+            });
+        }
+        let version = u16::from_le_bytes(bytes[8..10].try_into().unwrap());
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let header = Self {
+            version,
+            fingerprint_bits: bytes[10],
+            fingerprint_hash: bytes[11],
+            bucket_hash: bytes[12],
+            bucket_reduction: if version < FORMAT_VERSION {
+                BUCKET_REDUCTION_MODULO
+            } else {
+                bytes[13]
+            },
+            num_buckets: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        };
+        if version < FORMAT_VERSION {
+            if header.fingerprint_bits != FINGERPRINT_BITS {
+                return Err(FormatError::UnsupportedFingerprintBits(
+                    header.fingerprint_bits,
+                ));
+            }
+            if header.fingerprint_hash != FINGERPRINT_HASH_SIPHASH13 {
+                return Err(FormatError::UnknownFingerprintHash(header.fingerprint_hash));
+            }
+            if header.bucket_hash != BUCKET_HASH_SIPHASH13 {
+                return Err(FormatError::UnknownBucketHash(header.bucket_hash));
+            }
+        }
+        if !SUPPORTED_FINGERPRINT_BITS.contains(&header.fingerprint_bits) {
+            return Err(FormatError::UnsupportedFingerprintBits(
+                header.fingerprint_bits,
+            ));
+        }
+        header.hash_scheme()?;
+        header.bucket_count()?;
+        Ok(header)
+    }
+
+    /// Verify that a file belongs to an index with the given header.
+    pub(crate) fn check_matches(&self, expected: &FormatHeader) -> Result<(), FormatError> {
+        let fields = [
+            ("num_buckets", expected.num_buckets, self.num_buckets),
+            (
+                "fingerprint_bits",
+                expected.fingerprint_bits.into(),
+                self.fingerprint_bits.into(),
+            ),
+            (
+                "fingerprint_hash",
+                expected.fingerprint_hash.into(),
+                self.fingerprint_hash.into(),
+            ),
+            (
+                "bucket_hash",
+                expected.bucket_hash.into(),
+                self.bucket_hash.into(),
+            ),
+            (
+                "bucket_reduction",
+                expected.bucket_reduction.into(),
+                self.bucket_reduction.into(),
+            ),
+        ];
+        for (field, expected, found) in fields {
+            if expected != found {
+                return Err(FormatError::Mismatch {
+                    field,
+                    expected,
+                    found,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The persisted index is not in a format this version can read.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FormatError {
+    BadMagic {
+        expected: [u8; 8],
+        found: [u8; 8],
+    },
+    UnsupportedVersion(u16),
+    UnsupportedFingerprintBits(u8),
+    UnknownFingerprintHash(u8),
+    UnknownBucketHash(u8),
+    UnknownBucketReduction(u8),
+    /// The number of buckets can't be used with the bucket reduction, e.g. a power of two
+    /// reduction with a number of buckets that isn't a power of two.
+    InvalidBucketCount {
+        num_buckets: u64,
+        bucket_reduction: u8,
+    },
+    Mismatch {
+        field: &'static str,
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::BadMagic { expected, found } => write!(
+                f,
+                "not a partition index file: expected magic {:?}, found {:?}",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(found)
+            ),
+            FormatError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported format version {}, expected {} to {}",
+                version, MIN_FORMAT_VERSION, FORMAT_VERSION
+            ),
+            FormatError::UnsupportedFingerprintBits(bits) => write!(
+                f,
+                "unsupported fingerprint width of {} bits, expected one of {:?}",
+                bits, SUPPORTED_FINGERPRINT_BITS
+            ),
+            FormatError::UnknownFingerprintHash(id) => {
+                write!(f, "unknown fingerprint hash function {}", id)
+            }
+            FormatError::UnknownBucketHash(id) => write!(f, "unknown bucket hash function {}", id),
+            FormatError::UnknownBucketReduction(id) => {
+                write!(f, "unknown bucket reduction {}", id)
+            }
+            FormatError::InvalidBucketCount {
+                num_buckets,
+                bucket_reduction,
+            } => write!(
+                f,
+                "{} buckets can't be used with bucket reduction {}",
+                num_buckets, bucket_reduction
+            ),
+            FormatError::Mismatch {
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "inconsistent index: {} is {}, expected {}",
+                field, found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// A persisted file doesn't match its checksum.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorruptionError {
+    /// `partitions.data` is damaged, no partition of the index can be trusted.
+    PartitionData,
+    /// The metadata of a segment is damaged or the segment is truncated, affecting all
+    /// buckets of the given partitions.
+    Segment {
+        segment: u64,
+        partitions: Range<usize>,
+    },
+    /// The fingerprints of a single bucket are damaged, affecting the given partitions.
+    Bucket {
+        segment: u64,
+        bucket: u64,
+        partitions: Range<usize>,
+    },
+}
+
+impl fmt::Display for CorruptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorruptionError::PartitionData => write!(f, "corrupted partitions.data"),
+            CorruptionError::Segment {
+                segment,
+                partitions,
+            } => write!(
+                f,
+                "corrupted segment {} (partitions {:?})",
+                segment, partitions
+            ),
+            CorruptionError::Bucket {
+                segment,
+                bucket,
+                partitions,
+            } => write!(
+                f,
+                "corrupted bucket {} in segment {} (partitions {:?})",
+                bucket, segment, partitions
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CorruptionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FormatError, FormatHeader, MANIFEST_MAGIC, SEGMENT_MAGIC, SUPPORTED_FINGERPRINT_BITS,
+    };
+    use crate::filter::cuckoo::{BucketCount, BucketReduction, HashScheme};
+
+    #[test]
+    fn header_roundtrip() {
+        for scheme in [HashScheme::Legacy, HashScheme::Split128] {
+            for bits in SUPPORTED_FINGERPRINT_BITS {
+                let header = FormatHeader::new(4711, bits, scheme);
+                let bytes = header.to_bytes(SEGMENT_MAGIC);
+                let parsed = FormatHeader::from_bytes(&bytes, SEGMENT_MAGIC);
+                assert_eq!(parsed, Ok(header));
+                assert_eq!(parsed.unwrap().hash_scheme(), Ok(scheme));
+            }
+        }
+    }
+
+    #[test]
+    fn bucket_reduction_roundtrip() {
+        for reduction in [
+            BucketReduction::Modulo,
+            BucketReduction::PowerOfTwo,
+            BucketReduction::FastRange,
+        ] {
+            let buckets = BucketCount::new(4096, reduction);
+            let header = FormatHeader::new(buckets, 16, HashScheme::default());
+            let bytes = header.to_bytes(MANIFEST_MAGIC);
+            let parsed = FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC).unwrap();
+            assert_eq!(parsed.bucket_count(), Ok(buckets));
+        }
+    }
+
+    #[test]
+    fn reject_invalid_bucket_reductions() {
+        let mut bytes = FormatHeader::current(4711).to_bytes(MANIFEST_MAGIC);
+        bytes[13] = 0xFF;
+        assert_eq!(
+            FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC),
+            Err(FormatError::UnknownBucketReduction(0xFF))
+        );
+        // 4711 buckets can't be reduced by masking the hash
+        bytes[13] = 1;
+        assert_eq!(
+            FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC),
+            Err(FormatError::InvalidBucketCount {
+                num_buckets: 4711,
+                bucket_reduction: 1
+            })
+        );
+    }
+
+    #[test]
+    fn read_version_2_headers() {
+        let mut bytes = FormatHeader::new(4711, 16, HashScheme::Legacy).to_bytes(MANIFEST_MAGIC);
+        bytes[8..10].copy_from_slice(&2u16.to_le_bytes());
+        let header = FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC).unwrap();
+        assert_eq!(header.hash_scheme(), Ok(HashScheme::Legacy));
+        assert_eq!(header.bucket_count(), Ok(BucketCount::from(4711)));
+        // version 2 predates the other hash schemes and fingerprint widths
+        let mut split = FormatHeader::new(4711, 16, HashScheme::Split128).to_bytes(MANIFEST_MAGIC);
+        split[8..10].copy_from_slice(&2u16.to_le_bytes());
+        assert_eq!(
+            FormatHeader::from_bytes(&split, MANIFEST_MAGIC),
+            Err(FormatError::UnknownFingerprintHash(2))
+        );
+        let mut narrow = FormatHeader::new(4711, 8, HashScheme::Legacy).to_bytes(MANIFEST_MAGIC);
+        narrow[8..10].copy_from_slice(&2u16.to_le_bytes());
+        assert_eq!(
+            FormatHeader::from_bytes(&narrow, MANIFEST_MAGIC),
+            Err(FormatError::UnsupportedFingerprintBits(8))
+        );
+        bytes[8..10].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(
+            FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC),
+            Err(FormatError::UnsupportedVersion(1))
+        );
+    }
+
+    #[test]
+    fn fingerprint_sizes() {
+        let sizes: Vec<_> = SUPPORTED_FINGERPRINT_BITS
+            .iter()
+            .map(|bits| FormatHeader::new(1, *bits, HashScheme::default()).fingerprint_size())
+            .collect();
+        assert_eq!(sizes, vec![1, 2, 2, 4]);
+    }
+
+    #[test]
+    fn reject_unsupported_fingerprint_bits() {
+        let mut bytes = FormatHeader::current(4711).to_bytes(MANIFEST_MAGIC);
+        bytes[10] = 24;
+        assert_eq!(
+            FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC),
+            Err(FormatError::UnsupportedFingerprintBits(24))
+        );
+    }
+
+    #[test]
+    fn reject_unknown_hash_functions() {
+        let mut bytes = FormatHeader::current(4711).to_bytes(MANIFEST_MAGIC);
+        bytes[11] = 0xFF;
+        assert_eq!(
+            FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC),
+            Err(FormatError::UnknownFingerprintHash(0xFF))
+        );
+        // both hashes must belong to the same scheme
+        let mut bytes = FormatHeader::new(4711, 16, HashScheme::Legacy).to_bytes(MANIFEST_MAGIC);
+        bytes[12] = 2;
+        assert_eq!(
+            FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC),
+            Err(FormatError::UnknownBucketHash(2))
+        );
+    }
+
+    #[test]
+    fn reject_wrong_magic() {
+        let bytes = FormatHeader::current(4711).to_bytes(SEGMENT_MAGIC);
+        assert!(matches!(
+            FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC),
+            Err(FormatError::BadMagic { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_unsupported_version() {
+        let mut bytes = FormatHeader::current(4711).to_bytes(MANIFEST_MAGIC);
+        bytes[8] = 0xFF;
+        assert!(matches!(
+            FormatHeader::from_bytes(&bytes, MANIFEST_MAGIC),
+            Err(FormatError::UnsupportedVersion(_))
+        ));
+    }
+}
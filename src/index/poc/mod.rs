@@ -1,13 +1,29 @@
+pub mod format;
+mod segment;
+pub mod verify;
+
 use crate::{
-    filter::cuckoo::{bucket, fingerprint, flip_bucket},
-    index::{PartitionFilter, PartitionIndex},
+    filter::cuckoo::{fingerprint::Fingerprint, BucketCount, HashScheme, DEFAULT_SEED},
+    index::{
+        batch::{scan_bucket, QueryBatch},
+        PartitionFilter, PartitionId, PartitionIndex, PartitionLookup,
+    },
 };
 
+use self::{
+    format::{
+        CorruptionError, FormatError, FormatHeader, FORMAT_VERSION, HEADER_LEN, MANIFEST_MAGIC,
+    },
+    segment::{Segment, SegmentWriter},
+};
 use super::in_memory::{CuckooIndex, PartitionInfo};
+use rayon::prelude::*;
 use std::{
+    collections::HashSet,
     fs,
     io::{Read, Write},
-    path::PathBuf,
+    ops::Range,
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
@@ -17,79 +33,505 @@ pub struct PersistentIndexData<P> {
     slots: usize,
     partitions: Vec<PartitionInfo<P>>,
     elements: u64,
+    // in the order they were persisted, each covering a consecutive run of `partitions`
+    segments: Vec<SegmentInfo>,
+    // seeds the evictions of partitions added after loading the index
+    seed: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct SegmentInfo {
+    id: u64,
+    partitions: usize,
+    slots: usize,
+}
+
+/// `PersistentIndexData` as written in format version 2, without stashes and the seed.
+#[derive(serde::Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct PersistentIndexDataV2<P> {
+    num_buckets: u64,
+    slots: usize,
+    partitions: Vec<PartitionInfoV2<P>>,
+    elements: u64,
+    segments: Vec<SegmentInfo>,
+}
+
+#[derive(serde::Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct PartitionInfoV2<P> {
+    partition: P,
+    bucket_size: usize,
+    active: bool,
+    elements: u64,
+}
+
+impl<P> From<PersistentIndexDataV2<P>> for PersistentIndexData<P> {
+    fn from(data: PersistentIndexDataV2<P>) -> Self {
+        Self {
+            num_buckets: data.num_buckets,
+            slots: data.slots,
+            partitions: data
+                .partitions
+                .into_iter()
+                .map(|p| PartitionInfo {
+                    partition: p.partition,
+                    bucket_size: p.bucket_size,
+                    active: p.active,
+                    elements: p.elements,
+                    stash: vec![],
+                })
+                .collect(),
+            elements: data.elements,
+            segments: data.segments,
+            seed: DEFAULT_SEED,
+        }
+    }
+}
+
+/// How the persisted fingerprints are read when serving queries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Read both candidate buckets from the segment files for every query.
+    #[default]
+    Buffered,
+    /// Memory-map all segments when they're opened and serve queries from the mapping.
+    /// Opening only reads the metadata of each segment, unless all buckets are verified
+    /// on load, see [`LoadOptions::with_read_mode`].
+    Mmap,
+}
+
+/// Options for loading a persisted index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadOptions {
+    pub read_mode: ReadMode,
+    /// Verify the checksums of all buckets when loading, reading every segment in full.
+    /// The metadata of `partitions.data` and all segments is always verified.
+    pub verify_on_load: bool,
+    /// Verify the checksum of every bucket read while serving queries.
+    pub verify_on_read: bool,
+    /// Delete the segments and temporary files left behind by a `persist` or `compact`
+    /// that crashed before committing. Uncommitted files can't be told apart from
+    /// those of a writer that is still running, so only a writer that excludes all
+    /// other writers may recover, see [`PersistentIndex::try_load_for_writing`].
+    /// Without recovery, loading never modifies any files.
+    pub recover: bool,
+}
+
+impl LoadOptions {
+    /// The default options for reading with `read_mode`. Memory-mapped segments are
+    /// verified lazily, each bucket when a query reads it, since verifying them on load
+    /// would read all of them in full.
+    pub fn with_read_mode(read_mode: ReadMode) -> Self {
+        match read_mode {
+            ReadMode::Buffered => Self::default(),
+            ReadMode::Mmap => Self {
+                read_mode,
+                verify_on_load: false,
+                verify_on_read: true,
+                recover: false,
+            },
+        }
+    }
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            read_mode: ReadMode::default(),
+            verify_on_load: true,
+            verify_on_read: false,
+            recover: false,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct PersistentIndex<P> {
+/// A cuckoo index persisted to `storage_root`, storing fingerprints of type `F`.
+#[derive(Debug)]
+pub struct PersistentIndex<P, F = u16> {
     storage_root: String,
     data: PersistentIndexData<P>,
-    mem_index: CuckooIndex<P>,
-    data_root: PathBuf,
+    mem_index: CuckooIndex<P, F>,
+    segment_root: PathBuf,
+    // open segment files, in the same order as `data.segments`
+    segments: Vec<Segment<F>>,
+    options: LoadOptions,
 }
 
-impl<P> PersistentIndex<P>
+/// Read the format of the index persisted in `storage_root`, e.g. to find out which
+/// fingerprint type it has to be loaded with.
+pub fn read_format(storage_root: &str) -> anyhow::Result<FormatHeader> {
+    let mut header = [0u8; HEADER_LEN];
+    fs::File::open(PathBuf::from_str(storage_root)?.join("partitions.data"))?
+        .read_exact(&mut header)
+        .map_err(|_| CorruptionError::PartitionData)?;
+    Ok(FormatHeader::from_bytes(&header, MANIFEST_MAGIC)?)
+}
+
+impl<P, F: Fingerprint> PersistentIndex<P, F>
 where
     P: Clone + serde::Serialize + for<'de> serde::Deserialize<'de>,
 {
     pub fn try_new(buckets: u64, storage_root: String) -> anyhow::Result<Self> {
-        let data_root: PathBuf = [&storage_root, "index"].iter().collect();
+        Self::try_new_with_scheme(buckets, storage_root, HashScheme::default())
+    }
+
+    /// Create an index like `try_new` that hashes keys with `scheme`, which is recorded
+    /// in the format header of its files along with the width of `F`. `buckets` is
+    /// either the number of buckets or a [`BucketCount`] choosing how hashes are
+    /// reduced to them, which is recorded as well.
+    pub fn try_new_with_scheme(
+        buckets: impl Into<BucketCount>,
+        storage_root: String,
+        scheme: HashScheme,
+    ) -> anyhow::Result<Self> {
+        let buckets = buckets.into();
+        let segment_root: PathBuf = [&storage_root, "segments"].iter().collect();
         Ok(Self {
             storage_root,
             data: PersistentIndexData {
-                num_buckets: buckets,
+                num_buckets: buckets.count(),
                 slots: 0,
                 partitions: vec![],
                 elements: 0,
+                segments: vec![],
+                seed: DEFAULT_SEED,
             },
-            mem_index: CuckooIndex::new(buckets),
-            data_root,
+            mem_index: CuckooIndex::with_scheme(buckets, scheme),
+            segment_root,
+            segments: vec![],
+            options: LoadOptions::default(),
         })
     }
 
+    /// Load a persisted index without modifying any files, so it can be loaded while
+    /// another process persists or compacts it. The loaded index only contains what
+    /// was committed when loading.
     pub fn try_load_from_disk(storage_root: String) -> anyhow::Result<Self> {
-        // 1. figure out how to store the parts we're interested in on disk,
-        //    while keeping the rest (In-Memory bits) out of serialization
-        //    idea: have a sub-struct that constitutes the "persistent" bits, and
-        //    one that constitutes the ephemeral bits (in_memory::CuckooIndex)
-        let file = fs::OpenOptions::new()
-            .read(true)
-            .write(false)
-            .open(PathBuf::from_str(&storage_root)?.join("partitions.data"))?;
-        let data: PersistentIndexData<P> = bincode::deserialize_from(file)?;
+        Self::try_load_from_disk_with(storage_root, LoadOptions::default())
+    }
+
+    /// Load a persisted index to add to it, recovering from a `persist` or `compact`
+    /// that crashed before committing. Recovery deletes uncommitted files, so no other
+    /// process may write to the index at the same time.
+    pub fn try_load_for_writing(storage_root: String) -> anyhow::Result<Self> {
+        Self::try_load_from_disk_with(
+            storage_root,
+            LoadOptions {
+                recover: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Load a persisted index like `try_load_from_disk`, reading and verifying the
+    /// persisted fingerprints according to `options`.
+    ///
+    /// Damaged files are reported as a [`CorruptionError`].
+    pub fn try_load_from_disk_with(
+        storage_root: String,
+        options: LoadOptions,
+    ) -> anyhow::Result<Self> {
+        let (format, data) = Self::read_partition_data(&storage_root)?;
+        if format.fingerprint_bits != F::BITS {
+            return Err(FormatError::Mismatch {
+                field: "fingerprint_bits",
+                expected: F::BITS.into(),
+                found: format.fingerprint_bits.into(),
+            }
+            .into());
+        }
         let num_buckets = data.num_buckets;
-        let data_root: PathBuf = [&storage_root, "index"].iter().collect();
-        Ok(Self {
+        let segment_root: PathBuf = [&storage_root, "segments"].iter().collect();
+        let mem_index = CuckooIndex::with_scheme(format.bucket_count()?, format.hash_scheme()?)
+            .with_seed(data.seed);
+        let mut index = Self {
             storage_root,
             data,
-            mem_index: CuckooIndex::new(num_buckets),
-            data_root,
-        })
+            mem_index,
+            segment_root,
+            segments: vec![],
+            options,
+        };
+        if options.recover {
+            index.recover()?;
+        }
+        let mut first_partition = 0;
+        for info in &index.data.segments {
+            let segment = index.open_segment(info, first_partition)?;
+            first_partition += info.partitions;
+            anyhow::ensure!(
+                segment.num_buckets() == num_buckets && segment.slots() == info.slots,
+                "segment {} has {} buckets with {} slots, expected {} buckets with {} slots",
+                info.id,
+                segment.num_buckets(),
+                segment.slots(),
+                num_buckets,
+                info.slots,
+            );
+            index.segments.push(segment);
+        }
+        Ok(index)
+    }
+
+    /// Read and verify the committed `partitions.data`.
+    fn read_partition_data(
+        storage_root: &str,
+    ) -> anyhow::Result<(FormatHeader, PersistentIndexData<P>)> {
+        let content = fs::read(PathBuf::from_str(storage_root)?.join("partitions.data"))?;
+        if content.len() < HEADER_LEN + CHECKSUM_LEN {
+            return Err(CorruptionError::PartitionData.into());
+        }
+        let (header, content) = content.split_at(HEADER_LEN);
+        let format = FormatHeader::from_bytes(header.try_into()?, MANIFEST_MAGIC)?;
+        let (payload, checksum) = content.split_at(content.len() - CHECKSUM_LEN);
+        if crc32fast::hash(payload) != u32::from_le_bytes(checksum.try_into()?) {
+            return Err(CorruptionError::PartitionData.into());
+        }
+        let data: PersistentIndexData<P> = if format.version < FORMAT_VERSION {
+            bincode::deserialize::<PersistentIndexDataV2<P>>(payload)?.into()
+        } else {
+            bincode::deserialize(payload)?
+        };
+        if data.num_buckets != format.num_buckets {
+            return Err(FormatError::Mismatch {
+                field: "num_buckets",
+                expected: format.num_buckets,
+                found: data.num_buckets,
+            }
+            .into());
+        }
+        Ok((format, data))
     }
 
+    /// Persist the in-memory partitions in two phases: the buckets are written to a new
+    /// segment first, then `partitions.data` is replaced to commit the new segment.
     pub fn persist(&mut self) -> anyhow::Result<()> {
-        fs::create_dir_all(&self.data_root)?;
-        for (idx, bucket) in self.mem_index.buckets.iter().enumerate() {
-            let mut file = fs::OpenOptions::new()
-                .read(false)
-                .create(true)
-                .append(true)
-                .open(&self.data_root.join(format!("{:07}.bucket", idx)))?;
-            file.write_all(to_u8_slice(bucket))?;
+        let segment = self.write_segment()?;
+        self.commit(segment)
+    }
+
+    /// First phase of `persist`: write the in-memory buckets to a new segment.
+    /// The segment is not part of the index until `commit` succeeds; after a crash,
+    /// it's deleted when loading the index.
+    fn write_segment(&self) -> anyhow::Result<Option<SegmentInfo>> {
+        if self.mem_index.partitions.is_empty() {
+            return Ok(None);
+        }
+        fs::create_dir_all(&self.segment_root)?;
+        let id = self.next_segment_id();
+        let mut writer =
+            SegmentWriter::try_new(self.segment_path(id), self.format(), self.mem_index.slots)?;
+        for bucket in &self.mem_index.buckets {
+            writer.write_bucket(bucket)?;
+        }
+        writer.finish()?;
+        Ok(Some(SegmentInfo {
+            id,
+            partitions: self.mem_index.partitions.len(),
+            slots: self.mem_index.slots,
+        }))
+    }
+
+    /// Second phase of `persist`: move the in-memory partitions over to the persisted
+    /// state and atomically replace `partitions.data`.
+    fn commit(&mut self, segment: Option<SegmentInfo>) -> anyhow::Result<()> {
+        if let Some(info) = segment {
+            self.segments
+                .push(self.open_segment(&info, self.data.partitions.len())?);
+            self.data.segments.push(info);
         }
         self.data.partitions.append(&mut self.mem_index.partitions);
         self.data.slots += self.mem_index.slots;
         self.data.elements += self.mem_index.elements;
-        let file = fs::OpenOptions::new()
+        self.write_partition_data()?;
+        self.mem_index =
+            CuckooIndex::with_scheme(self.bucket_count(), self.scheme()).with_seed(self.seed());
+
+        Ok(())
+    }
+
+    /// Delete all files that aren't referenced by the committed `partitions.data`:
+    /// segments written by a `persist` or `compact` that crashed before committing,
+    /// and leftover temporary files.
+    fn recover(&self) -> anyhow::Result<()> {
+        if self.segment_root.exists() {
+            let committed: HashSet<_> = self
+                .data
+                .segments
+                .iter()
+                .map(|info| self.segment_path(info.id))
+                .collect();
+            for entry in self.segment_root.read_dir()? {
+                let path = entry?.path();
+                if !committed.contains(&path) {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        let tmp_path = PathBuf::from_str(&self.storage_root)?.join("partitions.data.tmp");
+        if tmp_path.exists() {
+            fs::remove_file(tmp_path)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite all segments into a single one, dropping the slots of inactive partitions.
+    ///
+    /// The index only switches over to the compacted segment when the new
+    /// `partitions.data` replaces the old one, so a reader loading the index sees either
+    /// the old or the new segments, never a partially written one. The previous segments
+    /// are deleted afterwards; readers that already opened them keep reading from their
+    /// open file handles.
+    ///
+    /// Only persisted partitions are compacted, the in-memory part is left untouched.
+    /// The remaining partitions are renumbered, invalidating previously returned
+    /// partition IDs.
+    pub fn compact(&mut self) -> anyhow::Result<()> {
+        if self.data.partitions.iter().all(|p| p.active) {
+            return Ok(());
+        }
+        // slot ranges to keep, per segment
+        let mut retained: Vec<Vec<Range<usize>>> = vec![];
+        let mut partitions = self.data.partitions.iter();
+        for info in &self.data.segments {
+            let mut ranges = vec![];
+            let mut pos = 0;
+            for p in partitions.by_ref().take(info.partitions) {
+                if p.active {
+                    ranges.push(pos..pos + p.bucket_size);
+                }
+                pos += p.bucket_size;
+            }
+            retained.push(ranges);
+        }
+        let slots: usize = retained.iter().flatten().map(|r| r.len()).sum();
+        let num_active = self.data.partitions.iter().filter(|p| p.active).count();
+
+        let mut compacted_segments = vec![];
+        if num_active > 0 {
+            let id = self.next_segment_id();
+            let mut writer = SegmentWriter::try_new(self.segment_path(id), self.format(), slots)?;
+            let mut buf = vec![];
+            let mut compacted = Vec::with_capacity(slots);
+            for idx in 0..self.data.num_buckets {
+                compacted.clear();
+                for (segment, ranges) in self.segments.iter().zip(&retained) {
+                    if ranges.is_empty() {
+                        continue;
+                    }
+                    let bucket = segment.bucket(idx, &mut buf)?;
+                    for range in ranges {
+                        compacted.extend_from_slice(&bucket[range.clone()]);
+                    }
+                }
+                writer.write_bucket(&compacted)?;
+            }
+            writer.finish()?;
+            compacted_segments.push(SegmentInfo {
+                id,
+                partitions: num_active,
+                slots,
+            });
+        }
+
+        let mut segments = vec![];
+        for info in &compacted_segments {
+            segments.push(self.open_segment(info, 0)?);
+        }
+        self.data.partitions.retain(|p| p.active);
+        self.data.slots = slots;
+        self.data.elements = self.data.partitions.iter().map(|p| p.elements).sum();
+        let previous = std::mem::replace(&mut self.data.segments, compacted_segments);
+        self.segments = segments;
+        self.write_partition_data()?;
+        for info in previous {
+            fs::remove_file(self.segment_path(info.id))?;
+        }
+        Ok(())
+    }
+
+    /// Write the metadata of all persisted partitions, including their `active` flags,
+    /// replacing the previous `partitions.data`.
+    /// The new state is written to a temporary file first and then renamed, so
+    /// `partitions.data` is always either the old or the new version.
+    fn write_partition_data(&self) -> anyhow::Result<()> {
+        let storage_root = PathBuf::from_str(&self.storage_root)?;
+        let tmp_path = storage_root.join("partitions.data.tmp");
+        let mut file = fs::OpenOptions::new()
             .read(false)
             .write(true)
             .create(true)
-            .open(PathBuf::from_str(&self.storage_root)?.join("partitions.data"))?;
-        bincode::serialize_into(file, &self.data)?;
-        self.mem_index = CuckooIndex::new(self.data.num_buckets);
-
+            .truncate(true)
+            .open(&tmp_path)?;
+        let payload = bincode::serialize(&self.data)?;
+        file.write_all(&self.format().to_bytes(MANIFEST_MAGIC))?;
+        file.write_all(&payload)?;
+        file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, storage_root.join("partitions.data"))?;
+        // make the rename itself durable
+        sync_dir(&storage_root)?;
         Ok(())
     }
 
+    /// The format of the files written by this index.
+    pub fn format(&self) -> FormatHeader {
+        FormatHeader::new(self.bucket_count(), F::BITS, self.scheme())
+    }
+
+    /// The hash scheme of the index, which is kept when persisting and compacting.
+    pub fn scheme(&self) -> HashScheme {
+        self.mem_index.scheme()
+    }
+
+    /// Seed the eviction when adding partitions, see [`CuckooIndex::with_seed`].
+    /// The seed is persisted with the partitions and used again when the index is
+    /// loaded, so partitions added later are laid out the same way.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.mem_index.seed = seed;
+        self.data.seed = seed;
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.mem_index.seed()
+    }
+
+    /// The buckets of the index and how hashes are reduced to them, which is kept when
+    /// persisting and compacting as well.
+    pub fn bucket_count(&self) -> BucketCount {
+        self.mem_index.bucket_count()
+    }
+
+    /// Open a committed segment whose partitions start at `first_partition`.
+    fn open_segment(
+        &self,
+        info: &SegmentInfo,
+        first_partition: usize,
+    ) -> anyhow::Result<Segment<F>> {
+        Segment::open(
+            &self.segment_path(info.id),
+            info.id,
+            first_partition..first_partition + info.partitions,
+            &self.format(),
+            &self.options,
+        )
+    }
+
+    fn next_segment_id(&self) -> u64 {
+        self.data.segments.last().map_or(0, |info| info.id + 1)
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.segment_root.join(segment::file_name(id))
+    }
+
+    /// The directory containing the segment files.
+    pub fn segment_root(&self) -> &Path {
+        &self.segment_root
+    }
+
     pub fn num_buckets(&self) -> u64 {
         self.data.num_buckets
     }
@@ -98,6 +540,10 @@ where
         self.data.slots
     }
 
+    pub fn num_segments(&self) -> usize {
+        self.data.segments.len()
+    }
+
     pub fn elements(&self) -> u64 {
         self.data.elements + self.mem_index.elements
     }
@@ -106,13 +552,20 @@ where
         (self.mem_index.partitions.capacity() + self.data.partitions.capacity())
             * std::mem::size_of::<P>()
             + self.data.num_buckets as usize
-                * (self.mem_index.buckets[0].capacity() * std::mem::size_of::<u16>()
-                    + std::mem::size_of::<Vec<u16>>())
+                * (self.mem_index.buckets[0].capacity() * std::mem::size_of::<F>()
+                    + std::mem::size_of::<Vec<F>>())
+            + self
+                .data
+                .partitions
+                .iter()
+                .chain(&self.mem_index.partitions)
+                .map(PartitionInfo::stash_mem_size)
+                .sum::<usize>()
     }
 
     pub fn estimate_disk_size(&self) -> usize {
         self.data.partitions.len() * std::mem::size_of::<P>()
-            + self.data.slots * self.data.num_buckets as usize * std::mem::size_of::<u16>()
+            + self.data.slots * self.data.num_buckets as usize * std::mem::size_of::<F>()
     }
 
     pub fn partitions(&self) -> impl Iterator<Item = P> + '_ {
@@ -123,75 +576,158 @@ where
             .map(|pi| pi.partition.clone())
     }
 
+    /// The partitions that weren't removed, persisted ones first.
+    pub fn active_partitions(&self) -> impl Iterator<Item = &P> + '_ {
+        self.data
+            .partitions
+            .iter()
+            .chain(self.mem_index.partitions.iter())
+            .filter(|pi| pi.active)
+            .map(|pi| &pi.partition)
+    }
+
+    /// The partitions that weren't removed along with their IDs, persisted ones first.
+    pub fn active_partitions_by_id(&self) -> impl Iterator<Item = (PartitionId, &P)> + '_ {
+        self.data
+            .partitions
+            .iter()
+            .chain(self.mem_index.partitions.iter())
+            .enumerate()
+            .filter(|(_, pi)| pi.active)
+            .map(|(id, pi)| (PartitionId(id), &pi.partition))
+    }
+
     pub fn num_partitions(&self) -> usize {
         self.data.partitions.len() + self.mem_index.partitions.len()
     }
 
-    fn load_bucket(&self, bucket: u64, buf: &mut Vec<u8>) -> anyhow::Result<()> {
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .write(false)
-            .open(&self.data_root.join(format!("{:07}.bucket", bucket)))?;
-        file.read_to_end(buf)?;
-        Ok(())
+    /// All persisted fingerprints of a single bucket, concatenated over all segments.
+    pub fn read_bucket(&self, bucket: u64) -> anyhow::Result<Vec<F>> {
+        let mut result = Vec::with_capacity(self.data.slots);
+        let mut buf = vec![];
+        for segment in &self.segments {
+            result.extend_from_slice(segment.bucket(bucket, &mut buf)?);
+        }
+        Ok(result)
+    }
+
+    /// Look up all values of a batch, reading each bucket once per segment.
+    /// Buckets are processed in parallel.
+    fn query_batch(&self, batch: &QueryBatch<F>) -> anyhow::Result<Vec<(usize, PartitionId)>>
+    where
+        P: Sync,
+    {
+        let offset = self.data.partitions.len();
+        let matches = batch
+            .buckets()
+            .par_iter()
+            .map(|(bucket, fingerprints)| {
+                let mut buf = vec![];
+                let mut matches = vec![];
+                let mut partitions = self.data.partitions.iter().enumerate();
+                for (segment, info) in self.segments.iter().zip(&self.data.segments) {
+                    let data = segment.bucket(*bucket, &mut buf)?;
+                    scan_bucket(
+                        fingerprints,
+                        *bucket,
+                        data,
+                        partitions.by_ref().take(info.partitions),
+                        &mut matches,
+                    );
+                }
+                scan_bucket(
+                    fingerprints,
+                    *bucket,
+                    &self.mem_index.buckets[*bucket as usize],
+                    (offset..).zip(&self.mem_index.partitions),
+                    &mut matches,
+                );
+                Ok(matches)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(matches.into_iter().flatten().collect())
     }
 
-    fn query_disk(&self, key: u64) -> anyhow::Result<Vec<P>> {
+    fn query_disk(&self, key: u64) -> anyhow::Result<Vec<PartitionId>> {
         if self.data.partitions.is_empty() {
             return Ok(vec![]);
         }
-        let fingerprint = fingerprint(key);
-        let bucket1 = bucket(key, self.data.num_buckets as u64);
-        let bucket2 = flip_bucket(fingerprint, bucket1, self.data.num_buckets as u64);
-        let mut b1_data = vec![];
-        let mut b2_data = vec![];
-        self.load_bucket(bucket1, &mut b1_data)?;
-        self.load_bucket(bucket2, &mut b2_data)?;
-        let b1_data_u16 = to_u16_slice(&b1_data);
-        let b2_data_u16 = to_u16_slice(&b2_data);
-        assert_eq!(b1_data_u16.len(), self.data.slots);
-        assert_eq!(b2_data_u16.len(), self.data.slots);
-        let mut pos = 0;
+        let scheme = self.scheme();
+        let (fingerprint, bucket1): (F, _) = scheme.locate(key, self.bucket_count());
+        let bucket2 = scheme.flip_bucket(fingerprint, bucket1, self.bucket_count());
+        let mut b1_buf = vec![];
+        let mut b2_buf = vec![];
         let mut result = vec![];
-        for p in &self.data.partitions {
-            if p.active {
-                for l in 0..p.bucket_size {
-                    if b1_data_u16[pos + l] == fingerprint || b2_data_u16[pos + l] == fingerprint {
-                        result.push(p.partition.clone());
-                    }
+        let mut partitions = self.data.partitions.iter().enumerate();
+        for (segment, info) in self.segments.iter().zip(&self.data.segments) {
+            let b1_data = segment.bucket(bucket1, &mut b1_buf)?;
+            let b2_data = segment.bucket(bucket2, &mut b2_buf)?;
+            let mut pos = 0;
+            for (id, p) in partitions.by_ref().take(info.partitions) {
+                // yield each partition once, even if several of its slots match
+                if p.active
+                    && ((pos..pos + p.bucket_size)
+                        .any(|l| b1_data[l] == fingerprint || b2_data[l] == fingerprint)
+                        || p.stashed(fingerprint, bucket1)
+                        || p.stashed(fingerprint, bucket2))
+                {
+                    result.push(PartitionId(id));
                 }
+                pos += p.bucket_size;
             }
-            pos += p.bucket_size;
         }
         Ok(result)
     }
 }
 
-fn to_u8_slice(slice: &[u16]) -> &[u8] {
-    let num_elems = 2 * slice.len();
-    unsafe { std::slice::from_raw_parts(slice.as_ptr().cast::<u8>(), num_elems) }
+const CHECKSUM_LEN: usize = std::mem::size_of::<u32>();
+
+fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
 }
 
-fn to_u16_slice(slice: &[u8]) -> &[u16] {
-    let num_elems = slice.len() / 2;
-    unsafe { std::slice::from_raw_parts(slice.as_ptr().cast::<u16>(), num_elems) }
+// Persisted partitions come first, followed by the in-memory ones. IDs stay the same
+// when persisting, but `compact` renumbers the remaining partitions.
+impl<P, F: Fingerprint> PartitionLookup<P> for PersistentIndex<P, F> {
+    fn partition(&self, id: PartitionId) -> Option<&P> {
+        match id.0.checked_sub(self.data.partitions.len()) {
+            None => Some(&self.data.partitions[id.0].partition),
+            Some(mem_id) => self.mem_index.partition(PartitionId(mem_id)),
+        }
+    }
 }
 
-impl<P> PartitionFilter<P> for PersistentIndex<P>
+impl<P, F: Fingerprint> PartitionFilter<P> for PersistentIndex<P, F>
 where
     P: Clone + serde::Serialize + for<'de> serde::Deserialize<'de>,
 {
-    fn query(&self, key: u64) -> anyhow::Result<Vec<P>> {
+    fn query_ids(&self, key: u64) -> anyhow::Result<Vec<PartitionId>> {
         let mut disk_results = self.query_disk(key)?;
-        let mut mem_results = self.mem_index.query(key)?;
-        disk_results.append(&mut mem_results);
+        let offset = self.data.partitions.len();
+        disk_results.extend(
+            self.mem_index
+                .query_ids(key)?
+                .into_iter()
+                .map(|id| PartitionId(offset + id.0)),
+        );
         Ok(disk_results)
     }
+
+    fn query_many_ids(&self, values: &[u64]) -> anyhow::Result<Vec<Vec<PartitionId>>>
+    where
+        P: Send + Sync,
+        Self: Sync,
+    {
+        let batch = QueryBatch::new(values, self.bucket_count(), self.scheme());
+        let matches = self.query_batch(&batch)?;
+        Ok(batch.collect(matches))
+    }
 }
 
-impl<P> PartitionIndex<P> for PersistentIndex<P>
+impl<P, F: Fingerprint> PartitionIndex<P> for PersistentIndex<P, F>
 where
-    P: PartialEq,
+    P: PartialEq + Clone + serde::Serialize + for<'de> serde::Deserialize<'de>,
 {
     fn add(&mut self, values: impl Iterator<Item = u64>, partition: P) {
         self.mem_index.add(values, partition)
@@ -205,19 +741,68 @@ where
         self.mem_index.add_many(partitions)
     }
 
-    fn remove(&mut self, to_be_removed: &P) {
-        self.mem_index.remove(to_be_removed)
+    /// Partitions that are already persisted are marked inactive in `partitions.data`
+    /// right away, so the removal survives reloading the index from disk.
+    fn remove(&mut self, to_be_removed: &P) -> anyhow::Result<()> {
+        self.remove_many(std::slice::from_ref(to_be_removed))
+    }
+
+    /// Like [`Self::remove`], but `partitions.data` is written only once for all of
+    /// them.
+    fn remove_many(&mut self, to_be_removed: &[P]) -> anyhow::Result<()> {
+        if self.stage_removal(to_be_removed)? {
+            self.write_partition_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl<P, F: Fingerprint> PersistentIndex<P, F>
+where
+    P: PartialEq + Clone + serde::Serialize + for<'de> serde::Deserialize<'de>,
+{
+    /// Remove partitions without writing `partitions.data`: the removal of persisted
+    /// partitions is committed by the next `persist`, along with the partitions added
+    /// until then. If that never happens, e.g. after a crash, loading the index still
+    /// yields them.
+    pub fn remove_on_persist(&mut self, to_be_removed: &[P]) -> anyhow::Result<()> {
+        self.stage_removal(to_be_removed)?;
+        Ok(())
+    }
+
+    /// Mark the partitions inactive in memory, returning whether any persisted
+    /// partition was affected.
+    fn stage_removal(&mut self, to_be_removed: &[P]) -> anyhow::Result<bool> {
+        self.mem_index.remove_many(to_be_removed)?;
+        let mut tombstoned = false;
+        for p in self.data.partitions.iter_mut() {
+            if p.active && to_be_removed.contains(&p.partition) {
+                p.active = false;
+                tombstoned = true;
+            }
+        }
+        Ok(tombstoned)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{os::linux::fs::MetadataExt, path::PathBuf};
+    use std::{fs, io::Write, os::linux::fs::MetadataExt};
 
-    use super::PersistentIndex;
-    use crate::index::{
-        tests::{self, TestPartition},
-        PartitionFilter, PartitionIndex,
+    use super::{
+        format::{CorruptionError, FormatError, FORMAT_VERSION},
+        LoadOptions, PersistentIndex, ReadMode,
+    };
+    use crate::{
+        filter::cuckoo::{
+            fingerprint::{Fingerprint, U12},
+            BucketCount, BucketReduction, HashScheme, DEFAULT_SEED,
+        },
+        index::{
+            in_memory::CuckooIndex,
+            tests::{self, TestPartition},
+            PartitionFilter, PartitionId, PartitionIndex, PartitionLookup,
+        },
     };
 
     static SEED: u64 = 1337;
@@ -228,12 +813,12 @@ mod tests {
         let partitions = &tests::create_test_data(100, (999, 4999), SEED);
         let mut index: PersistentIndex<TestPartition> =
             PersistentIndex::try_new(800, "".to_string())?;
-        tests::fill_index(&mut index, &partitions);
+        tests::fill_index(&mut index, partitions);
 
         for p in partitions {
-            if let Some(first_val) = tests::create_partition_data(&p).next() {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
                 assert!(
-                    index.query(first_val)?.contains(&p),
+                    index.query(first_val)?.contains(p),
                     "querying partitions for '{}' does not yield expected {:?}",
                     first_val,
                     &p.id
@@ -250,8 +835,8 @@ mod tests {
         let partitions = &tests::create_test_data(10, (99, 499), SEED);
         let mut index: PersistentIndex<TestPartition> =
             PersistentIndex::try_new(80, "".to_string())?;
-        tests::fill_index(&mut index, &partitions);
-        index.remove(&partitions[3]);
+        tests::fill_index(&mut index, partitions);
+        index.remove(&partitions[3])?;
         if let Some(first_val) = tests::create_partition_data(&partitions[3]).next() {
             assert!(
                 !index.query(first_val)?.contains(&partitions[3]),
@@ -263,6 +848,100 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn dont_yield_removed_persisted_partitions() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, partitions);
+        index.persist()?;
+        index.remove(&partitions[3])?;
+        drop(index);
+        let index_from_disk: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root.to_string())?;
+        for p in partitions {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
+                assert_eq!(
+                    index_from_disk.query(first_val)?.contains(p),
+                    p != &partitions[3],
+                    "querying partitions for '{}' yields unexpected result for {:?}",
+                    first_val,
+                    &p.id
+                );
+            } else {
+                panic!("could not create value for partition");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn remove_many_persisted_partitions() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, partitions);
+        index.persist()?;
+        let removed = [partitions[2].clone(), partitions[7].clone()];
+        index.remove_many(&removed)?;
+        drop(index);
+        let index_from_disk: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root.to_string())?;
+        for p in partitions {
+            let first_val = tests::create_partition_data(p)
+                .next()
+                .expect("could not create value for partition");
+            assert_eq!(
+                index_from_disk.query(first_val)?.contains(p),
+                !removed.contains(p),
+                "querying partitions for '{}' yields unexpected result for {:?}",
+                first_val,
+                &p.id
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dont_yield_removed_partitions_after_persist() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let (first_half, second_half) = partitions.split_at(5);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, first_half);
+        index.persist()?;
+        tests::fill_index(&mut index, second_half);
+        // one partition on disk, one still in memory
+        index.remove(&partitions[2])?;
+        index.remove(&partitions[7])?;
+        index.persist()?;
+        drop(index);
+        let index_from_disk: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root.to_string())?;
+        for removed in [&partitions[2], &partitions[7]] {
+            if let Some(first_val) = tests::create_partition_data(removed).next() {
+                assert!(
+                    !index_from_disk.query(first_val)?.contains(removed),
+                    "querying partitions for '{}' should not yield deleted partition {:?}",
+                    first_val,
+                    &removed.id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // header, bucket offset table, bucket checksums, metadata checksum, fingerprints
+    fn segment_file_length(buckets: u64, slots: usize) -> u64 {
+        32 + 8 * (buckets + 1) + 4 * (buckets + 1) + 2 * buckets * slots as u64
+    }
+
     #[test]
     fn verify_persisted_bucket_sizes() -> anyhow::Result<()> {
         let partitions = &tests::create_test_data(10, (99, 499), SEED);
@@ -270,22 +949,652 @@ mod tests {
         let storage_root = temp_dir.path();
         let mut index: PersistentIndex<TestPartition> =
             PersistentIndex::try_new(80, storage_root.to_str().unwrap().to_string())?;
-        tests::fill_index(&mut index, &partitions);
-        let expected_file_length = 2 * index.mem_index.slots;
+        tests::fill_index(&mut index, partitions);
+        let slots = index.mem_index.slots;
+        let expected_file_length = segment_file_length(80, slots);
         index.persist()?;
-        let data_root: PathBuf = storage_root.join("index");
-        for f in data_root.read_dir()? {
+        for f in index.segment_root().read_dir()? {
             let f = f?;
             let metadata = f.metadata()?;
             assert!(metadata.is_file(), "index should only contain files");
             assert_eq!(
                 metadata.st_size(),
-                expected_file_length as u64,
-                "index file '{:?}' must have length {}",
+                expected_file_length,
+                "segment file '{:?}' must have length {}",
                 f.file_name(),
                 expected_file_length,
             );
         }
+        for bucket in 0..80 {
+            assert_eq!(index.read_bucket(bucket)?.len(), slots);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn one_segment_per_persist() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(3, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        for p in partitions {
+            index.add(tests::create_partition_data(p), p.clone());
+            index.persist()?;
+        }
+        // persisting without new partitions does not create an empty segment
+        index.persist()?;
+        assert_eq!(index.num_segments(), partitions.len());
+        assert_eq!(index.segment_root().read_dir()?.count(), partitions.len());
+        for bucket in 0..80 {
+            assert_eq!(index.read_bucket(bucket)?.len(), index.num_slots());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compact_removed_partitions() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        let (first_half, second_half) = partitions.split_at(5);
+        tests::fill_index(&mut index, first_half);
+        index.persist()?;
+        tests::fill_index(&mut index, second_half);
+        index.persist()?;
+        let removed = [&partitions[3], &partitions[6]];
+        let removed_slots: usize = index
+            .data
+            .partitions
+            .iter()
+            .filter(|p| removed.contains(&&p.partition))
+            .map(|p| p.bucket_size)
+            .sum();
+        let slots_before = index.num_slots();
+        for p in removed {
+            index.remove(p)?;
+        }
+        index.compact()?;
+
+        assert_eq!(index.num_slots(), slots_before - removed_slots);
+        assert_eq!(index.num_partitions(), partitions.len() - removed.len());
+        assert_eq!(index.num_segments(), 1);
+        let segment_files: Vec<_> = index.segment_root().read_dir()?.collect();
+        assert_eq!(segment_files.len(), 1, "previous segments must be deleted");
+        for f in segment_files {
+            assert_eq!(
+                f?.metadata()?.st_size(),
+                segment_file_length(80, index.num_slots())
+            );
+        }
+
+        drop(index);
+        let index_from_disk: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root.to_string())?;
+        for p in partitions {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
+                assert_eq!(
+                    index_from_disk.query(first_val)?.contains(p),
+                    !removed.contains(&p),
+                    "querying partitions for '{}' yields unexpected result for {:?}",
+                    first_val,
+                    &p.id
+                );
+            } else {
+                panic!("could not create value for partition");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn recover_from_crash_before_commit() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let (first_half, second_half) = partitions.split_at(5);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, first_half);
+        index.persist()?;
+        let committed_slots = index.num_slots();
+        tests::fill_index(&mut index, second_half);
+        // simulate a crash after writing the segment, but before committing it
+        index.write_segment()?;
+        assert_eq!(index.segment_root().read_dir()?.count(), 2);
+        drop(index);
+
+        let index_from_disk: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_for_writing(storage_root.to_string())?;
+        assert_eq!(index_from_disk.num_slots(), committed_slots);
+        assert_eq!(index_from_disk.num_partitions(), first_half.len());
+        assert_eq!(index_from_disk.segment_root().read_dir()?.count(), 1);
+        for p in first_half {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
+                assert!(
+                    index_from_disk.query(first_val)?.contains(p),
+                    "querying partitions for '{}' does not yield expected {:?}",
+                    first_val,
+                    &p.id
+                );
+            } else {
+                panic!("could not create value for partition");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keep_removed_partitions_after_crash_before_commit() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let (first_half, second_half) = partitions.split_at(5);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, first_half);
+        index.persist()?;
+        // replace a partition, but crash after writing the segment of its replacement
+        index.remove_on_persist(&first_half[..1])?;
+        tests::fill_index(&mut index, second_half);
+        index.write_segment()?;
+        drop(index);
+
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_for_writing(storage_root.to_string())?;
+        let first_val = tests::create_partition_data(&first_half[0]).next().unwrap();
+        assert!(index.query(first_val)?.contains(&first_half[0]));
+
+        // without a crash, the removal is committed along with the new partitions
+        index.remove_on_persist(&first_half[..1])?;
+        tests::fill_index(&mut index, second_half);
+        index.persist()?;
+        drop(index);
+        let index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root.to_string())?;
+        assert!(!index.query(first_val)?.contains(&first_half[0]));
+        let second_val = tests::create_partition_data(&second_half[0])
+            .next()
+            .unwrap();
+        assert!(index.query(second_val)?.contains(&second_half[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn load_while_persisting() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let (first_half, second_half) = partitions.split_at(5);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, first_half);
+        index.persist()?;
+        tests::fill_index(&mut index, second_half);
+        let segment = index.write_segment()?;
+        // a reader loading the index between writing the segment and committing it
+        let reader: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root.to_string())?;
+        assert_eq!(reader.num_partitions(), first_half.len());
+        assert_eq!(index.segment_root().read_dir()?.count(), 2);
+        index.commit(segment)?;
+
+        let index_from_disk: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root.to_string())?;
+        assert_eq!(index_from_disk.num_partitions(), partitions.len());
+        for p in partitions {
+            let value = tests::create_partition_data(p).next().unwrap();
+            assert!(index_from_disk.query(value)?.contains(p));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn recover_from_partially_written_segment() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(5, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, partitions);
+        index.persist()?;
+        let committed_segment = index.segment_path(0);
+        // simulate a crash while writing a segment, and while committing the manifest
+        let partial_segment = index.segment_root().join("000001.segment.tmp");
+        fs::File::create(&partial_segment)?.write_all(&[1, 2, 3])?;
+        let partial_manifest = temp_dir.path().join("partitions.data.tmp");
+        fs::File::create(&partial_manifest)?.write_all(&[1, 2, 3])?;
+        drop(index);
+
+        let index_from_disk: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_for_writing(storage_root.to_string())?;
+        assert!(!partial_segment.exists());
+        assert!(!partial_manifest.exists());
+        assert!(committed_segment.exists());
+        for p in partitions {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
+                assert!(
+                    index_from_disk.query(first_val)?.contains(p),
+                    "querying partitions for '{}' does not yield expected {:?}",
+                    first_val,
+                    &p.id
+                );
+            } else {
+                panic!("could not create value for partition");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_incompatible_format() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(3, (10, 20), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(8, storage_root.to_string())?;
+        tests::fill_index(&mut index, partitions);
+        index.persist()?;
+        drop(index);
+
+        // bump the format version
+        let manifest = temp_dir.path().join("partitions.data");
+        let mut content = fs::read(&manifest)?;
+        content[8] += 1;
+        fs::write(&manifest, &content)?;
+        let err = PersistentIndex::<TestPartition>::try_load_from_disk(storage_root.to_string())
+            .expect_err("loading an index with a newer format version must fail");
+        assert_eq!(
+            err.downcast_ref::<FormatError>(),
+            Some(&FormatError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+
+        // not an index at all
+        fs::write(&manifest, b"this is not an index, just some text")?;
+        let err = PersistentIndex::<TestPartition>::try_load_from_disk(storage_root.to_string())
+            .expect_err("loading garbage must fail");
+        assert!(matches!(
+            err.downcast_ref::<FormatError>(),
+            Some(FormatError::BadMagic { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn detect_corrupted_partition_data() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(3, (10, 20), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(8, storage_root.to_string())?;
+        tests::fill_index(&mut index, partitions);
+        index.persist()?;
+        drop(index);
+
+        let manifest = temp_dir.path().join("partitions.data");
+        let mut content = fs::read(&manifest)?;
+        let last = content.len() - 5;
+        content[last] ^= 0xFF;
+        fs::write(&manifest, &content)?;
+        let err = PersistentIndex::<TestPartition>::try_load_from_disk(storage_root.to_string())
+            .expect_err("loading a corrupted index must fail");
+        assert_eq!(
+            err.downcast_ref::<CorruptionError>(),
+            Some(&CorruptionError::PartitionData)
+        );
+
+        content.truncate(10);
+        fs::write(&manifest, &content)?;
+        let err = PersistentIndex::<TestPartition>::try_load_from_disk(storage_root.to_string())
+            .expect_err("loading a truncated index must fail");
+        assert_eq!(
+            err.downcast_ref::<CorruptionError>(),
+            Some(&CorruptionError::PartitionData)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn detect_corrupted_segments() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(5, (10, 20), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(8, storage_root.to_string())?;
+        tests::fill_index(&mut index, &partitions[..2]);
+        index.persist()?;
+        tests::fill_index(&mut index, &partitions[2..]);
+        index.persist()?;
+        let segment_path = index.segment_path(1);
+        drop(index);
+
+        // flip a fingerprint in the last bucket of the second segment
+        let mut content = fs::read(&segment_path)?;
+        let last = content.len() - 1;
+        content[last] ^= 0xFF;
+        fs::write(&segment_path, &content)?;
+        let err = PersistentIndex::<TestPartition>::try_load_from_disk(storage_root.to_string())
+            .expect_err("corrupted bucket must be detected on load");
+        assert_eq!(
+            err.downcast_ref::<CorruptionError>(),
+            Some(&CorruptionError::Bucket {
+                segment: 1,
+                bucket: 7,
+                partitions: 2..5
+            })
+        );
+
+        // without verification on load, the bucket is only checked when it's read
+        let index = PersistentIndex::<TestPartition>::try_load_from_disk_with(
+            storage_root.to_string(),
+            LoadOptions {
+                verify_on_load: false,
+                verify_on_read: true,
+                ..Default::default()
+            },
+        )?;
+        let err = index
+            .read_bucket(7)
+            .expect_err("corrupted bucket must be detected on read");
+        assert!(matches!(
+            err.downcast_ref::<CorruptionError>(),
+            Some(CorruptionError::Bucket { bucket: 7, .. })
+        ));
+        drop(index);
+        // memory-mapped segments are verified lazily by default
+        let index = PersistentIndex::<TestPartition>::try_load_from_disk_with(
+            storage_root.to_string(),
+            LoadOptions::with_read_mode(ReadMode::Mmap),
+        )?;
+        assert!(index.read_bucket(6).is_ok());
+        assert!(index.read_bucket(7).is_err());
+        drop(index);
+
+        content.truncate(content.len() - 2);
+        fs::write(&segment_path, &content)?;
+        let err = PersistentIndex::<TestPartition>::try_load_from_disk(storage_root.to_string())
+            .expect_err("truncated segment must be detected on load");
+        assert_eq!(
+            err.downcast_ref::<CorruptionError>(),
+            Some(&CorruptionError::Segment {
+                segment: 1,
+                partitions: 2..5
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_partition_ids() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(6, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, &partitions[..4]);
+        index.persist()?;
+        // IDs continue with the in-memory partitions
+        tests::fill_index(&mut index, &partitions[4..]);
+        for (id, p) in partitions.iter().enumerate() {
+            let first_val = tests::create_partition_data(p).next().unwrap();
+            assert!(index.query_ids(first_val)?.contains(&PartitionId(id)));
+            assert_eq!(index.partition(PartitionId(id)), Some(p));
+        }
+        assert_eq!(index.partition(PartitionId(partitions.len())), None);
+        Ok(())
+    }
+
+    #[test]
+    fn query_many_values() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, &partitions[..4]);
+        index.persist()?;
+        tests::fill_index(&mut index, &partitions[4..8]);
+        index.persist()?;
+        tests::fill_index(&mut index, &partitions[8..]);
+        index.remove(&partitions[5])?;
+        // values of all partitions, plus a few that were never added
+        let values: Vec<u64> = partitions
+            .iter()
+            .flat_map(|p| tests::create_partition_data(p).take(20))
+            .chain(0..100)
+            .collect();
+        let expected: Vec<_> = values
+            .iter()
+            .map(|value| index.query_ids(*value))
+            .collect::<anyhow::Result<_>>()?;
+        assert_eq!(index.query_many_ids(&values)?, expected);
+        let expected: Vec<_> = values
+            .iter()
+            .map(|value| index.query(*value))
+            .collect::<anyhow::Result<_>>()?;
+        assert_eq!(index.query_many(&values)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn yield_persisted_partitions_once() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(3, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(8, storage_root.to_string())?;
+        tests::fill_index(&mut index, partitions);
+        // the fingerprint of the key in every slot of both of its buckets
+        let key = tests::create_partition_data(&partitions[1]).next().unwrap();
+        let fingerprint = index.scheme().fingerprint(key);
+        for bucket in &mut index.mem_index.buckets {
+            bucket.fill(fingerprint);
+        }
+        index.persist()?;
+        assert_eq!(index.query(key)?, partitions.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn load_index_with_legacy_hash_scheme() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap().to_string();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new_with_scheme(80, storage_root.clone(), HashScheme::Legacy)?;
+        tests::fill_index(&mut index, &partitions[..5]);
+        index.persist()?;
+
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root.clone())?;
+        assert_eq!(index.scheme(), HashScheme::Legacy);
+        // partitions added later are hashed the same way
+        tests::fill_index(&mut index, &partitions[5..]);
+        index.persist()?;
+        assert_eq!(index.format().hash_scheme(), Ok(HashScheme::Legacy));
+        let index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root)?;
+        for p in partitions {
+            let value = tests::create_partition_data(p).next().unwrap();
+            assert!(index.query(value)?.contains(p));
+        }
+        Ok(())
+    }
+
+    fn persist_and_load<F: Fingerprint>(partitions: &[TestPartition]) -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap().to_string();
+        let mut index: PersistentIndex<TestPartition, F> =
+            PersistentIndex::try_new(80, storage_root.clone())?;
+        tests::fill_index(&mut index, partitions);
+        index.persist()?;
+        assert_eq!(super::read_format(&storage_root)?.fingerprint_bits, F::BITS);
+
+        let index: PersistentIndex<TestPartition, F> =
+            PersistentIndex::try_load_from_disk(storage_root.clone())?;
+        for p in partitions {
+            let value = tests::create_partition_data(p).next().unwrap();
+            assert!(index.query(value)?.contains(p));
+        }
+        let err = PersistentIndex::<TestPartition, u16>::try_load_from_disk(storage_root)
+            .err()
+            .map(|err| err.downcast::<FormatError>());
+        if F::BITS != 16 {
+            assert!(matches!(
+                err,
+                Some(Ok(FormatError::Mismatch {
+                    field: "fingerprint_bits",
+                    ..
+                }))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn persist_fingerprint_widths() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        persist_and_load::<u8>(partitions)?;
+        persist_and_load::<U12>(partitions)?;
+        persist_and_load::<u16>(partitions)?;
+        persist_and_load::<u32>(partitions)?;
+        Ok(())
+    }
+
+    #[test]
+    fn persist_bucket_reductions() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        for reduction in [BucketReduction::PowerOfTwo, BucketReduction::FastRange] {
+            let temp_dir = tempfile::tempdir()?;
+            let storage_root = temp_dir.path().to_str().unwrap().to_string();
+            let buckets = BucketCount::new(64, reduction);
+            let mut index: PersistentIndex<TestPartition> = PersistentIndex::try_new_with_scheme(
+                buckets,
+                storage_root.clone(),
+                HashScheme::default(),
+            )?;
+            tests::fill_index(&mut index, &partitions[..5]);
+            index.persist()?;
+
+            let mut index: PersistentIndex<TestPartition> =
+                PersistentIndex::try_load_from_disk(storage_root)?;
+            assert_eq!(index.bucket_count(), buckets);
+            tests::fill_index(&mut index, &partitions[5..]);
+            for p in partitions {
+                let value = tests::create_partition_data(p).next().unwrap();
+                assert!(index.query(value)?.contains(p));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn persist_stashes() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(20, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap().to_string();
+        // few buckets, so some partitions end up with stashed fingerprints
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(8, storage_root.clone())?;
+        tests::fill_index(&mut index, partitions);
+        index.persist()?;
+        assert!(index.data.partitions.iter().any(|p| !p.stash.is_empty()));
+
+        let index_from_disk: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root)?;
+        assert_eq!(index.data, index_from_disk.data);
+        for p in partitions {
+            let values: Vec<u64> = tests::create_partition_data(p).collect();
+            let results = index_from_disk.query_many(&values)?;
+            for (value, result) in values.iter().zip(results) {
+                assert!(index_from_disk.query(*value)?.contains(p));
+                assert!(result.contains(p));
+            }
+        }
+
+        // the stashes take memory as well
+        let mut without_stashes = index_from_disk;
+        let estimate = without_stashes.estimate_mem_size();
+        without_stashes
+            .data
+            .partitions
+            .iter_mut()
+            .for_each(|p| p.stash.clear());
+        assert!(without_stashes.estimate_mem_size() < estimate);
+        Ok(())
+    }
+
+    #[test]
+    fn load_version_2_partition_data() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(3, (10, 20), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap().to_string();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new_with_scheme(1000, storage_root.clone(), HashScheme::Legacy)?;
+        tests::fill_index(&mut index, partitions);
+        index.persist()?;
+        drop(index);
+
+        // rewrite partitions.data without stashes, which are empty in such a sparse index
+        let (format, data) = PersistentIndex::<TestPartition>::read_partition_data(&storage_root)?;
+        assert!(data.partitions.iter().all(|p| p.stash.is_empty()));
+        let legacy = super::PersistentIndexDataV2 {
+            num_buckets: data.num_buckets,
+            slots: data.slots,
+            partitions: data
+                .partitions
+                .into_iter()
+                .map(|p| super::PartitionInfoV2 {
+                    partition: p.partition,
+                    bucket_size: p.bucket_size,
+                    active: p.active,
+                    elements: p.elements,
+                })
+                .collect(),
+            elements: data.elements,
+            segments: data.segments,
+        };
+        let header = super::FormatHeader {
+            version: 2,
+            ..format
+        };
+        let payload = bincode::serialize(&legacy)?;
+        let mut content = header.to_bytes(super::MANIFEST_MAGIC).to_vec();
+        content.extend_from_slice(&payload);
+        content.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        fs::write(temp_dir.path().join("partitions.data"), content)?;
+
+        let index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk(storage_root)?;
+        for p in partitions {
+            let value = tests::create_partition_data(p).next().unwrap();
+            assert!(index.query(value)?.contains(p));
+        }
+        assert_eq!(index.seed(), DEFAULT_SEED);
+        Ok(())
+    }
+
+    #[test]
+    fn persist_seed() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(4, (99, 499), SEED);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap().to_string();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.clone())?.with_seed(7);
+        tests::fill_index(&mut index, &partitions[..2]);
+        index.persist()?;
+        drop(index);
+
+        // partitions added after loading are laid out as if the index was never dropped
+        let mut loaded: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_for_writing(storage_root.clone())?;
+        assert_eq!(loaded.seed(), 7);
+        tests::fill_index(&mut loaded, &partitions[2..]);
+        let mut fresh: CuckooIndex<TestPartition> = CuckooIndex::new(80).with_seed(7);
+        tests::fill_index(&mut fresh, &partitions[2..]);
+        assert_eq!(loaded.mem_index.buckets, fresh.buckets);
         Ok(())
     }
 
@@ -296,7 +1605,7 @@ mod tests {
         let storage_root = temp_dir.path();
         let mut index: PersistentIndex<TestPartition> =
             PersistentIndex::try_new(80, storage_root.to_str().unwrap().to_string())?;
-        tests::fill_index(&mut index, &partitions);
+        tests::fill_index(&mut index, partitions);
         index.persist()?;
         // reload from disk and run query tests
         let index_from_disk: PersistentIndex<TestPartition> =
@@ -312,16 +1621,51 @@ mod tests {
         let storage_root = temp_dir.path();
         let mut index: PersistentIndex<TestPartition> =
             PersistentIndex::try_new(8, storage_root.to_str().unwrap().to_string())?;
-        tests::fill_index(&mut index, &partitions);
+        tests::fill_index(&mut index, partitions);
         index.persist()?;
         // reload from disk and run query tests
         let index_from_disk: PersistentIndex<TestPartition> =
             PersistentIndex::try_load_from_disk(storage_root.to_str().unwrap().to_string())?;
         drop(index);
         for p in partitions {
-            if let Some(first_val) = tests::create_partition_data(&p).next() {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
+                assert!(
+                    index_from_disk.query(first_val)?.contains(p),
+                    "querying partitions for '{}' does not yield expected {:?}",
+                    first_val,
+                    &p.id
+                );
+            } else {
+                panic!("could not create value for partition");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn serve_queries_from_mmap() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let (first_half, second_half) = partitions.split_at(5);
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
+        tests::fill_index(&mut index, first_half);
+        index.persist()?;
+        drop(index);
+        let mut index_from_disk: PersistentIndex<TestPartition> =
+            PersistentIndex::try_load_from_disk_with(
+                storage_root.to_string(),
+                LoadOptions::with_read_mode(ReadMode::Mmap),
+            )?;
+        // segments written after loading are mapped as well
+        tests::fill_index(&mut index_from_disk, second_half);
+        index_from_disk.persist()?;
+        assert_eq!(index_from_disk.num_segments(), 2);
+        for p in partitions {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
                 assert!(
-                    index_from_disk.query(first_val)?.contains(&p),
+                    index_from_disk.query(first_val)?.contains(p),
                     "querying partitions for '{}' does not yield expected {:?}",
                     first_val,
                     &p.id
@@ -350,9 +1694,9 @@ mod tests {
         tests::fill_index(&mut index_from_disk, second_half);
         // reload from disk and run query tests
         for p in partitions {
-            if let Some(first_val) = tests::create_partition_data(&p).next() {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
                 assert!(
-                    index_from_disk.query(first_val)?.contains(&p),
+                    index_from_disk.query(first_val)?.contains(p),
                     "querying partitions for '{}' does not yield expected {:?}",
                     first_val,
                     &p.id
@@ -370,16 +1714,17 @@ mod tests {
         let partitions = &tests::create_test_data(10, (99, 499), SEED);
         let temp_dir = tempfile::tempdir()?;
         let storage_root = temp_dir.path().to_str().unwrap();
-        let mut index = PersistentIndex::try_new(80, storage_root.to_string())?;
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(80, storage_root.to_string())?;
         for p in partitions {
-            index.add(tests::create_partition_data(&p), p.clone());
+            index.add(tests::create_partition_data(p), p.clone());
             index.persist()?;
             index = PersistentIndex::try_load_from_disk(storage_root.to_string())?;
         }
         for p in partitions {
-            if let Some(first_val) = tests::create_partition_data(&p).next() {
+            if let Some(first_val) = tests::create_partition_data(p).next() {
                 assert!(
-                    index.query(first_val)?.contains(&p),
+                    index.query(first_val)?.contains(p),
                     "querying partitions for '{}' does not yield expected {:?}",
                     first_val,
                     &p.id
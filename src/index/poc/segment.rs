@@ -0,0 +1,564 @@
+use memmap2::Mmap;
+use std::{
+    borrow::Cow,
+    fs,
+    io::{BufWriter, Write},
+    marker::PhantomData,
+    ops::Range,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    format::{CorruptionError, FormatHeader, HEADER_LEN, SEGMENT_MAGIC},
+    sync_dir, LoadOptions, ReadMode,
+};
+use crate::filter::cuckoo::fingerprint::Fingerprint;
+
+// Layout of a segment file:
+// - header: the format header (see `format`), followed by the number of slots per bucket
+// - bucket offset table: `buckets + 1` `u64` offsets relative to the start of the payload,
+//   the fingerprints of bucket `i` are stored in `offsets[i]..offsets[i + 1]`
+// - bucket checksum table: `buckets` CRC32 checksums of the fingerprints of each bucket
+// - metadata checksum: CRC32 of everything above
+// - payload: the fingerprints of all buckets, one bucket after the other, each taking
+//   `FormatHeader::fingerprint_size` bytes
+// All integers are little-endian.
+const HEADER_SIZE: u64 = (HEADER_LEN + std::mem::size_of::<u64>()) as u64;
+const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
+
+/// The name of the segment file with the given id.
+pub(crate) fn file_name(id: u64) -> String {
+    format!("{:06}.segment", id)
+}
+
+/// The length of a segment with `num_buckets` buckets of `slots` fingerprints each,
+/// taking `fingerprint_size` bytes per fingerprint.
+pub(crate) fn file_len(num_buckets: u64, slots: usize, fingerprint_size: usize) -> u64 {
+    let tables = (num_buckets + 1) * (std::mem::size_of::<u64>() + CHECKSUM_SIZE) as u64;
+    HEADER_SIZE + tables + num_buckets * (slots * fingerprint_size) as u64
+}
+
+/// An immutable file holding the fingerprints of all partitions persisted together.
+#[derive(Debug)]
+pub(crate) struct Segment<F> {
+    id: u64,
+    // the (global) partitions whose fingerprints are stored in this segment
+    partitions: Range<usize>,
+    data: SegmentData,
+    slots: usize,
+    // absolute file offsets of each bucket, with an additional end offset
+    offsets: Vec<u64>,
+    checksums: Vec<u32>,
+    verify_on_read: bool,
+    fingerprints: PhantomData<F>,
+}
+
+#[derive(Debug)]
+enum SegmentData {
+    File(fs::File),
+    Mmap(Mmap),
+}
+
+impl<F: Fingerprint> Segment<F> {
+    /// Open a segment, verifying that it was written for an index described by `expected`
+    /// and that its metadata is intact. The fingerprints of all buckets are only verified
+    /// if requested by `options`.
+    pub(crate) fn open(
+        path: &Path,
+        id: u64,
+        partitions: Range<usize>,
+        expected: &FormatHeader,
+        options: &LoadOptions,
+    ) -> anyhow::Result<Self> {
+        let corrupted = || CorruptionError::Segment {
+            segment: id,
+            partitions: partitions.clone(),
+        };
+        assert_eq!(expected.fingerprint_size(), std::mem::size_of::<F>());
+        let file = fs::File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, 0)
+            .map_err(|_| corrupted())?;
+        let format = FormatHeader::from_bytes(header[..HEADER_LEN].try_into()?, SEGMENT_MAGIC)?;
+        format.check_matches(expected)?;
+        let num_buckets = format.num_buckets as usize;
+        let slots = u64::from_le_bytes(header[HEADER_LEN..].try_into()?) as usize;
+        let offsets_len = (num_buckets + 1) * std::mem::size_of::<u64>();
+        let mut tables = vec![0u8; offsets_len + (num_buckets + 1) * CHECKSUM_SIZE];
+        file.read_exact_at(&mut tables, HEADER_SIZE)
+            .map_err(|_| corrupted())?;
+        let (tables, metadata_checksum) = tables.split_at(tables.len() - CHECKSUM_SIZE);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        hasher.update(tables);
+        if hasher.finalize() != u32::from_le_bytes(metadata_checksum.try_into()?) {
+            return Err(corrupted().into());
+        }
+        let (offsets, checksums) = tables.split_at(offsets_len);
+        let payload_start = HEADER_SIZE + (tables.len() + CHECKSUM_SIZE) as u64;
+        let offsets: Vec<_> = offsets
+            .chunks_exact(std::mem::size_of::<u64>())
+            .map(|offset| payload_start.checked_add(u64::from_le_bytes(offset.try_into().unwrap())))
+            .collect::<Option<_>>()
+            .ok_or_else(corrupted)?;
+        // every bucket holds exactly `slots` fingerprints, so reading a bucket never
+        // yields more or fewer fingerprints than the partitions of the segment have
+        let bucket_len = (slots as u64)
+            .checked_mul(std::mem::size_of::<F>() as u64)
+            .ok_or_else(corrupted)?;
+        if offsets
+            .windows(2)
+            .any(|bucket| bucket[1].checked_sub(bucket[0]) != Some(bucket_len))
+            || offsets[num_buckets] > file_len
+        {
+            return Err(corrupted().into());
+        }
+        let checksums = checksums
+            .chunks_exact(CHECKSUM_SIZE)
+            .map(|checksum| u32::from_le_bytes(checksum.try_into().unwrap()))
+            .collect();
+        let data = match options.read_mode {
+            ReadMode::Buffered => SegmentData::File(file),
+            // Safety: segments are never modified after they've been written. Deleting
+            // a mapped segment (e.g. during compaction) keeps the mapping intact.
+            ReadMode::Mmap => SegmentData::Mmap(unsafe { Mmap::map(&file)? }),
+        };
+        let segment = Self {
+            id,
+            partitions,
+            data,
+            slots,
+            offsets,
+            checksums,
+            verify_on_read: options.verify_on_read,
+            fingerprints: PhantomData,
+        };
+        if options.verify_on_load {
+            segment.verify()?;
+        }
+        Ok(segment)
+    }
+
+    /// Verify the checksums of all buckets.
+    pub(crate) fn verify(&self) -> anyhow::Result<()> {
+        let mut buf = vec![];
+        for bucket in 0..self.num_buckets() {
+            self.read_verified(bucket, &mut buf)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn num_buckets(&self) -> u64 {
+        self.offsets.len() as u64 - 1
+    }
+
+    pub(crate) fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// The fingerprints of a single bucket. A memory-mapped segment returns them straight
+    /// from the mapping, otherwise they're read into `buf`, replacing its previous content.
+    pub(crate) fn bucket<'a>(
+        &'a self,
+        bucket: u64,
+        buf: &'a mut Vec<F>,
+    ) -> anyhow::Result<&'a [F]> {
+        if self.verify_on_read {
+            self.read_verified(bucket, buf)
+        } else {
+            self.read(bucket, buf, false)
+        }
+    }
+
+    /// Like `bucket`, but always verifies the checksum of the bucket.
+    pub(crate) fn read_verified<'a>(
+        &'a self,
+        bucket: u64,
+        buf: &'a mut Vec<F>,
+    ) -> anyhow::Result<&'a [F]> {
+        self.read(bucket, buf, true)
+    }
+
+    fn read<'a>(
+        &'a self,
+        bucket: u64,
+        buf: &'a mut Vec<F>,
+        verify: bool,
+    ) -> anyhow::Result<&'a [F]> {
+        let start = self.offsets[bucket as usize] as usize;
+        let end = self.offsets[bucket as usize + 1] as usize;
+        let check = |bytes: &[u8]| {
+            if verify && crc32fast::hash(bytes) != self.checksums[bucket as usize] {
+                Err(CorruptionError::Bucket {
+                    segment: self.id,
+                    bucket,
+                    partitions: self.partitions.clone(),
+                })
+            } else {
+                Ok(())
+            }
+        };
+        match &self.data {
+            SegmentData::File(file) => {
+                buf.clear();
+                buf.resize((end - start) / std::mem::size_of::<F>(), F::EMPTY);
+                file.read_exact_at(to_u8_slice_mut(buf), start as u64)?;
+                check(to_u8_slice(buf))?;
+                if cfg!(target_endian = "big") {
+                    buf.iter_mut().for_each(|fp| *fp = F::from_le(*fp));
+                }
+                Ok(buf)
+            }
+            SegmentData::Mmap(mmap) if cfg!(target_endian = "little") => {
+                check(&mmap[start..end])?;
+                Ok(to_fingerprint_slice(&mmap[start..end]))
+            }
+            SegmentData::Mmap(mmap) => {
+                check(&mmap[start..end])?;
+                buf.clear();
+                buf.extend(
+                    to_fingerprint_slice::<F>(&mmap[start..end])
+                        .iter()
+                        .map(|fp| F::from_le(*fp)),
+                );
+                Ok(buf)
+            }
+        }
+    }
+}
+
+/// Writes a segment one bucket at a time. The segment is written to a temporary file
+/// and only shows up under its final name once `finish` succeeds.
+pub(crate) struct SegmentWriter {
+    file: BufWriter<fs::File>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    // header and offset table, the checksums are only known once all buckets are written
+    metadata: Vec<u8>,
+    checksums: Vec<u32>,
+    num_buckets: u64,
+    slots: usize,
+    fingerprint_size: usize,
+}
+
+impl SegmentWriter {
+    pub(crate) fn try_new(
+        path: PathBuf,
+        format: FormatHeader,
+        slots: usize,
+    ) -> anyhow::Result<Self> {
+        let num_buckets = format.num_buckets;
+        let tmp_path = path.with_extension("segment.tmp");
+        let mut file = BufWriter::new(
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?,
+        );
+        let mut metadata = vec![];
+        metadata.extend_from_slice(&format.to_bytes(SEGMENT_MAGIC));
+        metadata.extend_from_slice(&(slots as u64).to_le_bytes());
+        let bucket_len = (slots * format.fingerprint_size()) as u64;
+        for bucket in 0..=num_buckets {
+            metadata.extend_from_slice(&(bucket * bucket_len).to_le_bytes());
+        }
+        // reserve space for the checksums, they're filled in by `finish`
+        let checksums_len = (num_buckets as usize + 1) * CHECKSUM_SIZE;
+        file.write_all(&metadata)?;
+        file.write_all(&vec![0u8; checksums_len])?;
+        Ok(Self {
+            file,
+            path,
+            tmp_path,
+            metadata,
+            checksums: Vec::with_capacity(num_buckets as usize),
+            num_buckets,
+            slots,
+            fingerprint_size: format.fingerprint_size(),
+        })
+    }
+
+    /// Append the next bucket, which must contain exactly `slots` fingerprints of the
+    /// width given by the format of the segment.
+    pub(crate) fn write_bucket<F: Fingerprint>(
+        &mut self,
+        fingerprints: &[F],
+    ) -> anyhow::Result<()> {
+        assert!((self.checksums.len() as u64) < self.num_buckets);
+        assert_eq!(fingerprints.len(), self.slots);
+        assert_eq!(std::mem::size_of::<F>(), self.fingerprint_size);
+        let bytes = if cfg!(target_endian = "little") {
+            Cow::Borrowed(to_u8_slice(fingerprints))
+        } else {
+            let fingerprints: Vec<_> = fingerprints.iter().map(|fp| fp.to_le()).collect();
+            Cow::Owned(to_u8_slice(&fingerprints).to_vec())
+        };
+        self.checksums.push(crc32fast::hash(&bytes));
+        self.file.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Write the checksums, flush and sync the segment, then move it to its final location.
+    pub(crate) fn finish(self) -> anyhow::Result<()> {
+        assert_eq!(self.checksums.len() as u64, self.num_buckets);
+        let file = self.file.into_inner()?;
+        let mut checksums: Vec<u8> = self
+            .checksums
+            .iter()
+            .flat_map(|checksum| checksum.to_le_bytes())
+            .collect();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.metadata);
+        hasher.update(&checksums);
+        checksums.extend_from_slice(&hasher.finalize().to_le_bytes());
+        file.write_all_at(&checksums, self.metadata.len() as u64)?;
+        file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        if let Some(parent) = self.path.parent() {
+            sync_dir(parent)?;
+        }
+        Ok(())
+    }
+}
+
+// Fingerprints are plain integers (the trait is sealed), so their bytes can be
+// reinterpreted in both directions.
+fn to_u8_slice<F: Fingerprint>(slice: &[F]) -> &[u8] {
+    let num_elems = std::mem::size_of_val(slice);
+    unsafe { std::slice::from_raw_parts(slice.as_ptr().cast::<u8>(), num_elems) }
+}
+
+// The payload starts at an offset divisible by 4, buckets at multiples of the
+// fingerprint size and mappings are page-aligned, so the fingerprints are always
+// properly aligned.
+fn to_fingerprint_slice<F: Fingerprint>(slice: &[u8]) -> &[F] {
+    debug_assert_eq!(slice.as_ptr().align_offset(std::mem::align_of::<F>()), 0);
+    let num_elems = slice.len() / std::mem::size_of::<F>();
+    unsafe { std::slice::from_raw_parts(slice.as_ptr().cast::<F>(), num_elems) }
+}
+
+fn to_u8_slice_mut<F: Fingerprint>(slice: &mut [F]) -> &mut [u8] {
+    let num_elems = std::mem::size_of_val(slice);
+    unsafe { std::slice::from_raw_parts_mut(slice.as_mut_ptr().cast::<u8>(), num_elems) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::FileExt, path::Path};
+
+    use super::{Segment, SegmentWriter, CHECKSUM_SIZE, HEADER_SIZE};
+    use crate::{
+        filter::cuckoo::{
+            fingerprint::{Fingerprint, U12},
+            HashScheme,
+        },
+        index::poc::{
+            format::{CorruptionError, FormatError, FormatHeader},
+            LoadOptions, ReadMode,
+        },
+    };
+
+    fn open(
+        path: &Path,
+        format: &FormatHeader,
+        options: &LoadOptions,
+    ) -> anyhow::Result<Segment<u16>> {
+        Segment::open(path, 0, 0..1, format, options)
+    }
+
+    fn write_segment(path: &Path, format: FormatHeader) -> anyhow::Result<Vec<Vec<u16>>> {
+        let num_buckets = format.num_buckets as u16;
+        let buckets: Vec<Vec<u16>> = (0..num_buckets).map(|b| vec![b, b + 100, 0]).collect();
+        let mut writer = SegmentWriter::try_new(path.to_path_buf(), format, 3)?;
+        for bucket in &buckets {
+            writer.write_bucket(bucket)?;
+        }
+        assert!(
+            !path.exists(),
+            "segment must not be visible before finishing"
+        );
+        writer.finish()?;
+        Ok(buckets)
+    }
+
+    #[test]
+    fn segment_roundtrip() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("000000.segment");
+        let format = FormatHeader::current(7);
+        let buckets = write_segment(&path, format)?;
+
+        for read_mode in [ReadMode::Buffered, ReadMode::Mmap] {
+            let options = LoadOptions {
+                read_mode,
+                verify_on_load: true,
+                verify_on_read: true,
+                ..Default::default()
+            };
+            let segment = open(&path, &format, &options)?;
+            assert_eq!(segment.num_buckets(), 7);
+            assert_eq!(segment.slots(), 3);
+            let mut buf = vec![];
+            for (idx, bucket) in buckets.iter().enumerate() {
+                assert_eq!(segment.bucket(idx as u64, &mut buf)?, bucket);
+            }
+        }
+        Ok(())
+    }
+
+    fn fingerprint_roundtrip<F: Fingerprint>(path: &Path) -> anyhow::Result<()> {
+        let format = FormatHeader::new(5, F::BITS, HashScheme::default());
+        let buckets: Vec<Vec<F>> = (0..5)
+            .map(|b| vec![F::from_bits(b + 1), F::EMPTY, F::from_bits(u64::MAX - b)])
+            .collect();
+        let mut writer = SegmentWriter::try_new(path.to_path_buf(), format, 3)?;
+        for bucket in &buckets {
+            writer.write_bucket(bucket)?;
+        }
+        writer.finish()?;
+        assert_eq!(
+            fs::metadata(path)?.len(),
+            super::file_len(5, 3, std::mem::size_of::<F>())
+        );
+        for read_mode in [ReadMode::Buffered, ReadMode::Mmap] {
+            let options = LoadOptions {
+                read_mode,
+                verify_on_load: true,
+                verify_on_read: true,
+                ..Default::default()
+            };
+            let segment: Segment<F> = Segment::open(path, 0, 0..1, &format, &options)?;
+            let mut buf = vec![];
+            for (idx, bucket) in buckets.iter().enumerate() {
+                assert_eq!(segment.bucket(idx as u64, &mut buf)?, bucket);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn segment_roundtrip_fingerprint_widths() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        fingerprint_roundtrip::<u8>(&temp_dir.path().join("000000.segment"))?;
+        fingerprint_roundtrip::<U12>(&temp_dir.path().join("000001.segment"))?;
+        fingerprint_roundtrip::<u32>(&temp_dir.path().join("000002.segment"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn reject_segment_of_other_index() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("000000.segment");
+        let mut writer = SegmentWriter::try_new(path.clone(), FormatHeader::current(1), 1)?;
+        writer.write_bucket(&[42u16])?;
+        writer.finish()?;
+        let err = open(&path, &FormatHeader::current(2), &LoadOptions::default())
+            .expect_err("number of buckets differs");
+        assert!(matches!(
+            err.downcast_ref::<FormatError>(),
+            Some(FormatError::Mismatch {
+                field: "num_buckets",
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn detect_corrupted_bucket() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("000000.segment");
+        let format = FormatHeader::current(7);
+        write_segment(&path, format)?;
+        // flip the last fingerprint of the last bucket
+        let file = fs::OpenOptions::new().write(true).open(&path)?;
+        file.write_all_at(&[0xFF], file.metadata()?.len() - 1)?;
+
+        let err = open(&path, &format, &LoadOptions::default())
+            .expect_err("corruption must be detected on load");
+        assert_eq!(
+            err.downcast_ref::<CorruptionError>(),
+            Some(&CorruptionError::Bucket {
+                segment: 0,
+                bucket: 6,
+                partitions: 0..1
+            })
+        );
+
+        for read_mode in [ReadMode::Buffered, ReadMode::Mmap] {
+            let options = LoadOptions {
+                read_mode,
+                verify_on_load: false,
+                verify_on_read: true,
+                ..Default::default()
+            };
+            let segment = open(&path, &format, &options)?;
+            let mut buf = vec![];
+            assert!(segment.bucket(5, &mut buf).is_ok());
+            let err = segment
+                .bucket(6, &mut buf)
+                .expect_err("corruption must be detected on read");
+            assert!(matches!(
+                err.downcast_ref::<CorruptionError>(),
+                Some(CorruptionError::Bucket { bucket: 6, .. })
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn detect_inconsistent_offsets() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("000000.segment");
+        let format = FormatHeader::current(7);
+        write_segment(&path, format)?;
+        // move the end of the first bucket by one fingerprint, with a valid checksum
+        let mut content = fs::read(&path)?;
+        let offset = HEADER_SIZE as usize + std::mem::size_of::<u64>();
+        let end = u64::from_le_bytes(content[offset..offset + 8].try_into()?);
+        content[offset..offset + 8].copy_from_slice(&(end + 2).to_le_bytes());
+        let checksum_offset =
+            HEADER_SIZE as usize + 8 * std::mem::size_of::<u64>() + 7 * CHECKSUM_SIZE;
+        let checksum = crc32fast::hash(&content[..checksum_offset]);
+        content[checksum_offset..checksum_offset + CHECKSUM_SIZE]
+            .copy_from_slice(&checksum.to_le_bytes());
+        fs::write(&path, content)?;
+
+        for read_mode in [ReadMode::Buffered, ReadMode::Mmap] {
+            let options = LoadOptions {
+                read_mode,
+                verify_on_load: false,
+                ..Default::default()
+            };
+            let err = open(&path, &format, &options)
+                .expect_err("buckets of the wrong size must be detected on load");
+            assert!(matches!(
+                err.downcast_ref::<CorruptionError>(),
+                Some(CorruptionError::Segment { segment: 0, .. })
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn detect_corrupted_metadata() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("000000.segment");
+        let format = FormatHeader::current(7);
+        write_segment(&path, format)?;
+        // corrupt the offset of the first bucket
+        let file = fs::OpenOptions::new().write(true).open(&path)?;
+        file.write_all_at(&[0xFF], 32)?;
+
+        let err = open(&path, &format, &LoadOptions::default())
+            .expect_err("corruption must be detected on load");
+        assert!(matches!(
+            err.downcast_ref::<CorruptionError>(),
+            Some(CorruptionError::Segment { segment: 0, .. })
+        ));
+        Ok(())
+    }
+}
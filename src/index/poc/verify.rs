@@ -0,0 +1,464 @@
+use std::{collections::HashSet, fs, ops::Range, path::PathBuf, str::FromStr};
+
+use super::{
+    format::{CorruptionError, FormatHeader},
+    segment::{self, Segment},
+    LoadOptions, PersistentIndex, PersistentIndexData,
+};
+use crate::{
+    filter::cuckoo::fingerprint::{Fingerprint, U12},
+    index::{in_memory::CuckooIndex, PartitionId},
+};
+
+/// A problem found while verifying a persisted index.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    /// `partitions.data` can't be read, none of the other checks were run.
+    UnreadablePartitionData {
+        error: String,
+    },
+    MissingSegment {
+        segment: u64,
+    },
+    UnreadableSegment {
+        segment: u64,
+        error: String,
+    },
+    SegmentLength {
+        segment: u64,
+        expected: u64,
+        found: u64,
+    },
+    /// The number of slots per bucket stored in the segment differs from `partitions.data`.
+    SegmentSlots {
+        segment: u64,
+        expected: usize,
+        found: usize,
+    },
+    CorruptedBucket {
+        segment: u64,
+        bucket: u64,
+        partitions: Range<usize>,
+    },
+    BucketLength {
+        segment: u64,
+        bucket: u64,
+        expected: usize,
+        found: usize,
+    },
+    /// The bucket sizes of the partitions stored in a segment don't add up to its slots.
+    PartitionSlots {
+        segment: u64,
+        expected: usize,
+        found: usize,
+    },
+    /// The segments don't cover all partitions.
+    SegmentPartitions {
+        expected: usize,
+        found: usize,
+    },
+    /// The slots of all segments don't add up to the slots of the index.
+    Slots {
+        expected: usize,
+        found: usize,
+    },
+    /// The elements of all partitions don't add up to the elements of the index.
+    Elements {
+        expected: u64,
+        found: u64,
+    },
+    Query {
+        value: u64,
+        error: String,
+    },
+    /// Querying `missing` of the `checked` values of a partition didn't yield the partition.
+    FalseNegatives {
+        partition: usize,
+        missing: u64,
+        checked: u64,
+    },
+}
+
+/// The result of verifying a persisted index, see [`verify`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct VerifyReport {
+    pub storage_root: String,
+    pub num_buckets: u64,
+    pub slots: usize,
+    pub partitions: usize,
+    pub active_partitions: usize,
+    pub elements: u64,
+    pub segments: usize,
+    /// The number of values checked for false negatives.
+    pub values_checked: u64,
+    /// Files left behind by a `persist` or `compact` that crashed before committing.
+    /// They're not an issue, loading the index deletes them.
+    pub uncommitted_files: Vec<PathBuf>,
+    pub issues: Vec<Issue>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Check that a persisted index is consistent: the metadata in `partitions.data` adds up,
+/// every segment exists, has the expected length and all of its buckets match their
+/// checksums.
+///
+/// Unlike loading the index, this never modifies any files, so it's safe to run on an
+/// index that crashed while persisting.
+pub fn verify<P>(storage_root: &str) -> VerifyReport
+where
+    P: Clone + serde::Serialize + for<'de> serde::Deserialize<'de>,
+{
+    verify_impl(storage_root, None::<fn(&P) -> std::iter::Empty<u64>>)
+}
+
+/// Like [`verify`], additionally re-deriving the values of every active partition with
+/// `values` and checking that querying each of them yields the partition.
+///
+/// The values are only checked if the index is structurally sound.
+pub fn verify_with_values<P, V, I>(storage_root: &str, values: V) -> VerifyReport
+where
+    P: Clone + serde::Serialize + for<'de> serde::Deserialize<'de>,
+    V: Fn(&P) -> I,
+    I: IntoIterator<Item = u64>,
+{
+    verify_impl(storage_root, Some(values))
+}
+
+fn verify_impl<P, V, I>(storage_root: &str, values: Option<V>) -> VerifyReport
+where
+    P: Clone + serde::Serialize + for<'de> serde::Deserialize<'de>,
+    V: Fn(&P) -> I,
+    I: IntoIterator<Item = u64>,
+{
+    let mut report = VerifyReport {
+        storage_root: storage_root.to_string(),
+        ..Default::default()
+    };
+    // the fingerprint width is only needed to read the segments, see below
+    let (format, data) = match PersistentIndex::<P>::read_partition_data(storage_root) {
+        Ok(partition_data) => partition_data,
+        Err(err) => {
+            report.issues.push(Issue::UnreadablePartitionData {
+                error: format!("{:#}", err),
+            });
+            return report;
+        }
+    };
+    report.num_buckets = data.num_buckets;
+    report.slots = data.slots;
+    report.partitions = data.partitions.len();
+    report.active_partitions = data.partitions.iter().filter(|p| p.active).count();
+    report.elements = data.elements;
+    report.segments = data.segments.len();
+
+    let covered: usize = data.segments.iter().map(|info| info.partitions).sum();
+    if covered != data.partitions.len() {
+        report.issues.push(Issue::SegmentPartitions {
+            expected: data.partitions.len(),
+            found: covered,
+        });
+    }
+    let slots: usize = data.segments.iter().map(|info| info.slots).sum();
+    if slots != data.slots {
+        report.issues.push(Issue::Slots {
+            expected: data.slots,
+            found: slots,
+        });
+    }
+    let elements: u64 = data.partitions.iter().map(|p| p.elements).sum();
+    if elements != data.elements {
+        report.issues.push(Issue::Elements {
+            expected: data.elements,
+            found: elements,
+        });
+    }
+
+    match format.fingerprint_bits {
+        8 => verify_segments::<P, u8, V, I>(storage_root, report, format, data, values),
+        12 => verify_segments::<P, U12, V, I>(storage_root, report, format, data, values),
+        16 => verify_segments::<P, u16, V, I>(storage_root, report, format, data, values),
+        32 => verify_segments::<P, u32, V, I>(storage_root, report, format, data, values),
+        bits => unreachable!("reading the header rejects {} bit fingerprints", bits),
+    }
+}
+
+/// The checks of `verify_impl` that read the segments, with fingerprints of type `F`.
+fn verify_segments<P, F, V, I>(
+    storage_root: &str,
+    mut report: VerifyReport,
+    format: FormatHeader,
+    data: PersistentIndexData<P>,
+    values: Option<V>,
+) -> VerifyReport
+where
+    P: Clone + serde::Serialize + for<'de> serde::Deserialize<'de>,
+    F: Fingerprint,
+    V: Fn(&P) -> I,
+    I: IntoIterator<Item = u64>,
+{
+    let segment_root: PathBuf = [storage_root, "segments"].iter().collect();
+    // the buckets are verified one by one below to report all corrupted buckets
+    let options = LoadOptions {
+        verify_on_load: false,
+        ..Default::default()
+    };
+    let mut segments = vec![];
+    let mut first_partition = 0;
+    let mut buf = vec![];
+    for info in &data.segments {
+        let end = (first_partition + info.partitions).min(data.partitions.len());
+        let partitions = first_partition.min(end)..end;
+        first_partition += info.partitions;
+        let bucket_sizes = data.partitions[partitions.clone()]
+            .iter()
+            .map(|p| p.bucket_size)
+            .sum();
+        if bucket_sizes != info.slots {
+            report.issues.push(Issue::PartitionSlots {
+                segment: info.id,
+                expected: info.slots,
+                found: bucket_sizes,
+            });
+        }
+
+        let path = segment_root.join(segment::file_name(info.id));
+        let Ok(metadata) = fs::metadata(&path) else {
+            report
+                .issues
+                .push(Issue::MissingSegment { segment: info.id });
+            continue;
+        };
+        let expected_len =
+            segment::file_len(data.num_buckets, info.slots, format.fingerprint_size());
+        if metadata.len() != expected_len {
+            report.issues.push(Issue::SegmentLength {
+                segment: info.id,
+                expected: expected_len,
+                found: metadata.len(),
+            });
+            continue;
+        }
+        let segment: Segment<F> = match Segment::open(&path, info.id, partitions, &format, &options)
+        {
+            Ok(segment) => segment,
+            Err(err) => {
+                report.issues.push(Issue::UnreadableSegment {
+                    segment: info.id,
+                    error: format!("{:#}", err),
+                });
+                continue;
+            }
+        };
+        if segment.slots() != info.slots {
+            report.issues.push(Issue::SegmentSlots {
+                segment: info.id,
+                expected: info.slots,
+                found: segment.slots(),
+            });
+            continue;
+        }
+        for bucket in 0..segment.num_buckets() {
+            match segment.read_verified(bucket, &mut buf) {
+                Ok(fingerprints) if fingerprints.len() != info.slots => {
+                    report.issues.push(Issue::BucketLength {
+                        segment: info.id,
+                        bucket,
+                        expected: info.slots,
+                        found: fingerprints.len(),
+                    })
+                }
+                Ok(_) => {}
+                Err(err) => match err.downcast::<CorruptionError>() {
+                    Ok(CorruptionError::Bucket {
+                        segment,
+                        bucket,
+                        partitions,
+                    }) => report.issues.push(Issue::CorruptedBucket {
+                        segment,
+                        bucket,
+                        partitions,
+                    }),
+                    err => {
+                        report.issues.push(Issue::UnreadableSegment {
+                            segment: info.id,
+                            error: match err {
+                                Ok(err) => err.to_string(),
+                                Err(err) => format!("{:#}", err),
+                            },
+                        });
+                        break;
+                    }
+                },
+            }
+        }
+        segments.push(segment);
+    }
+
+    let committed: HashSet<_> = data
+        .segments
+        .iter()
+        .map(|info| segment_root.join(segment::file_name(info.id)))
+        .collect();
+    if let Ok(entries) = segment_root.read_dir() {
+        for entry in entries.flatten() {
+            if !committed.contains(&entry.path()) {
+                report.uncommitted_files.push(entry.path());
+            }
+        }
+    }
+    let tmp_path = PathBuf::from_str(storage_root)
+        .unwrap()
+        .join("partitions.data.tmp");
+    if tmp_path.exists() {
+        report.uncommitted_files.push(tmp_path);
+    }
+
+    let Some(values) = values else {
+        return report;
+    };
+    if !report.is_ok() {
+        return report;
+    }
+    let index = PersistentIndex {
+        storage_root: storage_root.to_string(),
+        mem_index: CuckooIndex::with_scheme(
+            format
+                .bucket_count()
+                .expect("the header was validated when reading it"),
+            format
+                .hash_scheme()
+                .expect("the header was validated when reading it"),
+        ),
+        data,
+        segment_root,
+        segments,
+        options,
+    };
+    for (idx, partition) in index.data.partitions.iter().enumerate() {
+        if !partition.active {
+            continue;
+        }
+        let mut checked = 0;
+        let mut missing = 0;
+        for value in values(&partition.partition) {
+            checked += 1;
+            match index.query_disk(value) {
+                Ok(ids) if ids.contains(&PartitionId(idx)) => {}
+                Ok(_) => missing += 1,
+                Err(err) => {
+                    report.issues.push(Issue::Query {
+                        value,
+                        error: format!("{:#}", err),
+                    });
+                    return report;
+                }
+            }
+        }
+        report.values_checked += checked;
+        if missing > 0 {
+            report.issues.push(Issue::FalseNegatives {
+                partition: idx,
+                missing,
+                checked,
+            });
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{verify, verify_with_values, Issue};
+    use crate::index::{
+        poc::PersistentIndex,
+        tests::{self, TestPartition},
+    };
+
+    static SEED: u64 = 1337;
+
+    fn create_index(storage_root: &str) -> anyhow::Result<Vec<TestPartition>> {
+        let partitions = tests::create_test_data(5, (10, 20), SEED);
+        let mut index: PersistentIndex<TestPartition> =
+            PersistentIndex::try_new(8, storage_root.to_string())?;
+        tests::fill_index(&mut index, &partitions[..2]);
+        index.persist()?;
+        tests::fill_index(&mut index, &partitions[2..]);
+        index.persist()?;
+        Ok(partitions)
+    }
+
+    #[test]
+    fn verify_consistent_index() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        let partitions = create_index(storage_root)?;
+
+        let report = verify_with_values(storage_root, |p: &TestPartition| {
+            tests::create_partition_data(p)
+        });
+        assert!(report.is_ok(), "unexpected issues: {:?}", report.issues);
+        assert_eq!(report.partitions, 5);
+        assert_eq!(report.segments, 2);
+        assert_eq!(
+            report.values_checked,
+            partitions.iter().map(|p| p.size as u64).sum::<u64>()
+        );
+        assert!(report.uncommitted_files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn report_corrupted_buckets() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        create_index(storage_root)?;
+
+        let segment_path = temp_dir.path().join("segments").join("000001.segment");
+        let mut content = fs::read(&segment_path)?;
+        let last = content.len() - 1;
+        content[last] ^= 0xFF;
+        fs::write(&segment_path, &content)?;
+        fs::write(temp_dir.path().join("partitions.data.tmp"), b"leftover")?;
+
+        let report = verify::<TestPartition>(storage_root);
+        assert_eq!(
+            report.issues,
+            vec![Issue::CorruptedBucket {
+                segment: 1,
+                bucket: 7,
+                partitions: 2..5
+            }]
+        );
+        assert_eq!(report.uncommitted_files.len(), 1);
+
+        fs::remove_file(&segment_path)?;
+        let report = verify::<TestPartition>(storage_root);
+        assert_eq!(report.issues, vec![Issue::MissingSegment { segment: 1 }]);
+        Ok(())
+    }
+
+    #[test]
+    fn report_false_negatives() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let storage_root = temp_dir.path().to_str().unwrap();
+        create_index(storage_root)?;
+
+        // values that were never added to the index
+        let report = verify_with_values(storage_root, |_: &TestPartition| u64::MAX - 10..u64::MAX);
+        assert_eq!(report.values_checked, 50);
+        assert_eq!(report.issues.len(), 5);
+        assert!(report
+            .issues
+            .iter()
+            .all(|issue| matches!(issue, Issue::FalseNegatives { .. })));
+        Ok(())
+    }
+}
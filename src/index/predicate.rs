@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+
+use super::{key::column_key, multi_column::MultiColumnIndex, PartitionFilter, PartitionId};
+
+/// A predicate over the hashed values of named columns, used to prune the partitions
+/// that can't contain matching rows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    /// `column = value`
+    Eq(String, u64),
+    /// `column IN (values...)`
+    In(String, Vec<u64>),
+    /// All predicates must match, requires at least one predicate.
+    And(Vec<Predicate>),
+    /// Any predicate must match, requires at least one predicate.
+    Or(Vec<Predicate>),
+}
+
+/// The candidate partitions of a predicate, along with the evaluation of each nested
+/// predicate of `And` and `Or` in the same order, empty for `Eq` and `In`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Evaluation {
+    /// The IDs of the partitions that may contain matching rows, in ascending order.
+    pub partitions: Vec<PartitionId>,
+    pub children: Vec<Evaluation>,
+}
+
+impl Predicate {
+    /// Evaluate the predicate against `filter`, looking up the values as they are,
+    /// regardless of their column.
+    /// All values of the predicate are looked up in a single batch query.
+    pub fn evaluate<P, F>(&self, filter: &F) -> anyhow::Result<Evaluation>
+    where
+        P: Send + Sync,
+        F: PartitionFilter<P> + Sync,
+    {
+        self.evaluate_with(filter, |_, value| value)
+    }
+
+    /// Evaluate the predicate against `filter`, which holds the values of all columns
+    /// keyed by their column, see [`column_key`].
+    pub fn evaluate_keyed<P, F>(&self, filter: &F) -> anyhow::Result<Evaluation>
+    where
+        P: Send + Sync,
+        F: PartitionFilter<P> + Sync,
+    {
+        self.evaluate_with(filter, column_key)
+    }
+
+    fn evaluate_with<P, F>(
+        &self,
+        filter: &F,
+        key: impl Fn(&str, u64) -> u64,
+    ) -> anyhow::Result<Evaluation>
+    where
+        P: Send + Sync,
+        F: PartitionFilter<P> + Sync,
+    {
+        let mut values = vec![];
+        self.collect_values(&mut values)?;
+        let keys: Vec<_> = values
+            .into_iter()
+            .map(|(column, value)| key(column, value))
+            .collect();
+        let mut results = filter.query_many_ids(&keys)?.into_iter();
+        Ok(self.combine(&mut results))
+    }
+
+    /// Evaluate the predicate against the columns of `index`, failing if it refers to
+    /// a column that isn't indexed.
+    /// The values of each column are looked up in a single batch query.
+    pub fn evaluate_columns<P>(&self, index: &MultiColumnIndex<P>) -> anyhow::Result<Evaluation>
+    where
+        P: Send + Sync,
+    {
+        let mut values = vec![];
+        self.collect_values(&mut values)?;
+        let mut by_column: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (position, (column, value)) in values.iter().enumerate() {
+            by_column
+                .entry(*column)
+                .or_default()
+                .push((position, *value));
+        }
+        let mut results = vec![vec![]; values.len()];
+        for (column, values) in by_column {
+            let filter = index
+                .column(column)
+                .ok_or_else(|| anyhow::anyhow!("column '{}' is not indexed", column))?;
+            let keys: Vec<_> = values.iter().map(|(_, value)| *value).collect();
+            for ((position, _), ids) in values.iter().zip(filter.query_many_ids(&keys)?) {
+                results[*position] = ids;
+            }
+        }
+        Ok(self.combine(&mut results.into_iter()))
+    }
+
+    fn collect_values<'a>(&'a self, values: &mut Vec<(&'a str, u64)>) -> anyhow::Result<()> {
+        match self {
+            Predicate::Eq(column, value) => values.push((column, *value)),
+            Predicate::In(column, in_values) => {
+                values.extend(in_values.iter().map(|value| (column.as_str(), *value)))
+            }
+            Predicate::And(predicates) | Predicate::Or(predicates) => {
+                anyhow::ensure!(
+                    !predicates.is_empty(),
+                    "AND and OR require at least one predicate"
+                );
+                for predicate in predicates {
+                    predicate.collect_values(values)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // consumes the query results in the same order as `collect_values` produced the values
+    fn combine(&self, results: &mut impl Iterator<Item = Vec<PartitionId>>) -> Evaluation {
+        let leaf = |partitions| Evaluation {
+            partitions,
+            children: vec![],
+        };
+        match self {
+            Predicate::Eq(_, _) => leaf(results.next().unwrap()),
+            Predicate::In(_, values) => leaf(union(results.take(values.len()))),
+            Predicate::And(predicates) => {
+                let children: Vec<_> = predicates.iter().map(|p| p.combine(results)).collect();
+                let mut partitions = children[0].partitions.clone();
+                for child in &children[1..] {
+                    partitions.retain(|id| child.partitions.binary_search(id).is_ok());
+                }
+                Evaluation {
+                    partitions,
+                    children,
+                }
+            }
+            Predicate::Or(predicates) => {
+                let children: Vec<_> = predicates.iter().map(|p| p.combine(results)).collect();
+                Evaluation {
+                    partitions: union(children.iter().map(|c| c.partitions.clone())),
+                    children,
+                }
+            }
+        }
+    }
+}
+
+fn union(sets: impl Iterator<Item = Vec<PartitionId>>) -> Vec<PartitionId> {
+    sets.kmerge().dedup().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Predicate;
+    use crate::index::{
+        in_memory::CuckooIndex,
+        key::column_key,
+        multi_column::MultiColumnIndex,
+        tests::{self, TestPartition},
+        PartitionFilter, PartitionId, PartitionIndex,
+    };
+
+    static SEED: u64 = 1337;
+
+    #[test]
+    fn evaluate_predicates() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let mut index: CuckooIndex<TestPartition> = CuckooIndex::new(80);
+        tests::fill_index(&mut index, partitions);
+        let value =
+            |p: usize, n: usize| tests::create_partition_data(&partitions[p]).nth(n).unwrap();
+        let eq = |value| Predicate::Eq("a".to_string(), value);
+
+        let eq_result = eq(value(1, 0)).evaluate(&index)?;
+        assert_eq!(eq_result.partitions, index.query_ids(value(1, 0))?);
+        assert!(eq_result.children.is_empty());
+
+        let in_list = Predicate::In("a".to_string(), vec![value(1, 0), value(4, 0), value(7, 0)])
+            .evaluate(&index)?;
+        for id in [1, 4, 7] {
+            assert!(in_list.partitions.contains(&PartitionId(id)));
+        }
+        assert!(in_list.partitions.windows(2).all(|ids| ids[0] < ids[1]));
+
+        // both values are in partition 2, only one of them in partition 3
+        let and = Predicate::And(vec![
+            eq(value(2, 0)),
+            Predicate::In("a".to_string(), vec![value(2, 1), value(3, 0)]),
+        ])
+        .evaluate(&index)?;
+        assert!(and.partitions.contains(&PartitionId(2)));
+        assert_eq!(and.children.len(), 2);
+        for id in &and.partitions {
+            assert!(and.children.iter().all(|c| c.partitions.contains(id)));
+        }
+        assert!(and.children[1].partitions.contains(&PartitionId(3)));
+
+        let or = Predicate::Or(vec![eq(value(5, 0)), eq(value(6, 0))]).evaluate(&index)?;
+        let mut expected = [
+            or.children[0].partitions.clone(),
+            or.children[1].partitions.clone(),
+        ]
+        .concat();
+        expected.sort();
+        expected.dedup();
+        assert_eq!(or.partitions, expected);
+        assert!(or.partitions.contains(&PartitionId(5)));
+        assert!(or.partitions.contains(&PartitionId(6)));
+        Ok(())
+    }
+
+    #[test]
+    fn evaluate_keyed_predicates() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let mut index: CuckooIndex<TestPartition> = CuckooIndex::new(80);
+        for p in partitions {
+            index.add(
+                tests::create_partition_data(p).map(|value| column_key("a", value)),
+                p.clone(),
+            );
+        }
+        let value = tests::create_partition_data(&partitions[1]).next().unwrap();
+
+        let eq = Predicate::Eq("a".to_string(), value).evaluate_keyed(&index)?;
+        assert_eq!(eq.partitions, index.query_ids(column_key("a", value))?);
+        assert!(eq.partitions.contains(&PartitionId(1)));
+        // the values are only indexed in column a
+        let other_column = Predicate::Eq("b".to_string(), value).evaluate_keyed(&index)?;
+        assert!(!other_column.partitions.contains(&PartitionId(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn evaluate_predicates_over_columns() -> anyhow::Result<()> {
+        let partitions = &tests::create_test_data(10, (99, 499), SEED);
+        let mut index = MultiColumnIndex::new(80);
+        for p in partitions {
+            // column b holds the same values shifted by one
+            index.add(
+                [
+                    ("a", tests::create_partition_data(p).collect::<Vec<_>>()),
+                    (
+                        "b",
+                        tests::create_partition_data(p)
+                            .map(|v| v.wrapping_add(1))
+                            .collect(),
+                    ),
+                ]
+                .map(|(name, values)| (name, values.into_iter())),
+                p.clone(),
+            );
+        }
+        let value =
+            |p: usize, n: usize| tests::create_partition_data(&partitions[p]).nth(n).unwrap();
+
+        // a = x AND b = y, with x and y from the same partition
+        let and = Predicate::And(vec![
+            Predicate::Eq("a".to_string(), value(2, 0)),
+            Predicate::In(
+                "b".to_string(),
+                vec![value(2, 1).wrapping_add(1), value(3, 0)],
+            ),
+        ])
+        .evaluate_columns(&index)?;
+        assert!(and.partitions.contains(&PartitionId(2)));
+        assert_eq!(
+            and.children[0].partitions,
+            index.query_ids("a", value(2, 0))?
+        );
+
+        // each leaf is looked up in its own column
+        let swapped = Predicate::Eq("b".to_string(), value(2, 0)).evaluate_columns(&index)?;
+        assert!(!swapped.partitions.contains(&PartitionId(2)));
+
+        assert!(Predicate::Eq("c".to_string(), value(2, 0))
+            .evaluate_columns(&index)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_empty_conjunction() {
+        let index: CuckooIndex<TestPartition> = CuckooIndex::new(80);
+        assert!(Predicate::And(vec![]).evaluate(&index).is_err());
+        assert!(Predicate::Or(vec![Predicate::And(vec![])])
+            .evaluate(&index)
+            .is_err());
+    }
+}
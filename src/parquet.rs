@@ -0,0 +1,1041 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use arrow2::{
+    array::{Array, BinaryArray, BooleanArray, PrimitiveArray, Utf8Array},
+    datatypes::{DataType, Field, Schema},
+    io::parquet::read::{self, RowGroupMetaData},
+    types::NativeType,
+};
+
+use crate::index::{
+    key::{column_key, Date, Decimal, Hashable, Value, ValueType},
+    poc::PersistentIndex,
+    predicate::Predicate,
+    PartitionFilter, PartitionIndex, PartitionLookup,
+};
+
+/// A partition of Parquet data: a whole file, or a single row group of a file.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ParquetPartition {
+    pub path: PathBuf,
+    /// The index of the row group within the file, `None` if the partition covers
+    /// the whole file.
+    pub row_group: Option<usize>,
+    /// The version of the file when it was indexed.
+    pub version: FileVersion,
+    /// The statistics of the indexed columns, as stored in the file's metadata.
+    pub statistics: BTreeMap<String, ColumnStatistics>,
+}
+
+impl ParquetPartition {
+    /// Whether the statistics of `column` allow the partition to contain `value`.
+    pub fn may_contain(&self, column: &str, value: &Value) -> bool {
+        self.statistics
+            .get(column)
+            .is_none_or(|statistics| statistics.may_contain(value))
+    }
+}
+
+/// The statistics of a column within a partition. Unknown statistics are `None`,
+/// e.g. if the file doesn't contain them or if the column has no non-null values.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ColumnStatistics {
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    pub null_count: Option<u64>,
+}
+
+impl ColumnStatistics {
+    /// Whether `value` lies within the bounds, if they are known and comparable to it.
+    pub fn may_contain(&self, value: &Value) -> bool {
+        !(self.min.as_ref().is_some_and(|min| value < min)
+            || self.max.as_ref().is_some_and(|max| value > max))
+    }
+
+    /// The combined statistics of `field` over `row_groups`.
+    fn read(field: &Field, row_groups: &[RowGroupMetaData]) -> anyhow::Result<Self> {
+        let statistics = read::statistics::deserialize(field, row_groups)?;
+        let values = |array: &dyn Array| -> Option<Vec<Value>> {
+            (0..row_groups.len())
+                .map(|idx| array_value(array, idx))
+                .collect()
+        };
+        let null_count = statistics
+            .null_count
+            .as_any()
+            .downcast_ref::<PrimitiveArray<u64>>()
+            .and_then(|counts| counts.iter().map(|count| count.copied()).sum());
+        Ok(Self {
+            min: values(statistics.min_value.as_ref()).and_then(|v| extreme(v, Ordering::Less)),
+            max: values(statistics.max_value.as_ref()).and_then(|v| extreme(v, Ordering::Greater)),
+            null_count,
+        })
+    }
+}
+
+/// The smallest (`Less`) or largest (`Greater`) of `values`, `None` if they can't be
+/// compared.
+fn extreme(values: Vec<Value>, ordering: Ordering) -> Option<Value> {
+    let mut values = values.into_iter();
+    let mut result = values.next()?;
+    for value in values {
+        if value.partial_cmp(&result)? == ordering {
+            result = value;
+        }
+    }
+    Some(result)
+}
+
+/// The value at `idx` of an array of statistics, `None` if it's null or of an
+/// unsupported type.
+fn array_value(array: &dyn Array, idx: usize) -> Option<Value> {
+    fn primitive<T: NativeType>(array: &dyn Array, idx: usize) -> T {
+        array
+            .as_any()
+            .downcast_ref::<PrimitiveArray<T>>()
+            .unwrap()
+            .value(idx)
+    }
+
+    if array.is_null(idx) {
+        return None;
+    }
+    Some(match array.data_type().to_logical_type() {
+        DataType::Boolean => Value::Bool(
+            array
+                .as_any()
+                .downcast_ref::<BooleanArray>()
+                .unwrap()
+                .value(idx),
+        ),
+        DataType::Int8 => Value::Int(primitive::<i8>(array, idx).into()),
+        DataType::Int16 => Value::Int(primitive::<i16>(array, idx).into()),
+        DataType::Int32 => Value::Int(primitive::<i32>(array, idx).into()),
+        DataType::Int64 => Value::Int(primitive::<i64>(array, idx).into()),
+        DataType::UInt8 => Value::Int(primitive::<u8>(array, idx).into()),
+        DataType::UInt16 => Value::Int(primitive::<u16>(array, idx).into()),
+        DataType::UInt32 => Value::Int(primitive::<u32>(array, idx).into()),
+        DataType::UInt64 => Value::Int(primitive::<u64>(array, idx).into()),
+        DataType::Float32 => Value::Float(primitive::<f32>(array, idx).into()),
+        DataType::Float64 => Value::Float(primitive::<f64>(array, idx)),
+        DataType::Date32 => Value::Date(Date(primitive::<i32>(array, idx))),
+        DataType::Decimal(_, scale) => Value::Decimal(Decimal {
+            value: primitive::<i128>(array, idx),
+            scale: u8::try_from(*scale).ok()?,
+        }),
+        DataType::Utf8 => Value::String(
+            array
+                .as_any()
+                .downcast_ref::<Utf8Array<i32>>()
+                .unwrap()
+                .value(idx)
+                .to_string(),
+        ),
+        DataType::LargeUtf8 => Value::String(
+            array
+                .as_any()
+                .downcast_ref::<Utf8Array<i64>>()
+                .unwrap()
+                .value(idx)
+                .to_string(),
+        ),
+        DataType::Binary => Value::Binary(
+            array
+                .as_any()
+                .downcast_ref::<BinaryArray<i32>>()
+                .unwrap()
+                .value(idx)
+                .to_vec(),
+        ),
+        DataType::LargeBinary => Value::Binary(
+            array
+                .as_any()
+                .downcast_ref::<BinaryArray<i64>>()
+                .unwrap()
+                .value(idx)
+                .to_vec(),
+        ),
+        _ => return None,
+    })
+}
+
+/// The partitions that may contain a value, along with how many partitions each
+/// stage of [`query_parquet`] eliminated.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct PrunedQuery {
+    pub partitions: Vec<ParquetPartition>,
+    /// The number of partitions that weren't removed from the index.
+    pub total: usize,
+    /// Partitions whose statistics exclude the value.
+    pub pruned_by_statistics: usize,
+    /// Partitions that weren't pruned by their statistics, but whose fingerprints
+    /// don't contain the value.
+    pub pruned_by_index: usize,
+}
+
+/// Identifies the contents of a file by its size and modification time, so files that
+/// were rewritten since they were indexed can be told apart from unchanged ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct FileVersion {
+    pub size: u64,
+    /// `None` on platforms that don't record modification times.
+    pub modified: Option<SystemTime>,
+}
+
+impl FileVersion {
+    pub fn of(path: &Path) -> anyhow::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// The files an index update added and removed, see [`update_parquet`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpdateSummary {
+    /// New files, and files that were rewritten since they were indexed.
+    pub added: Vec<PathBuf>,
+    /// Files that were deleted, and files that were rewritten since they were indexed.
+    pub removed: Vec<PathBuf>,
+    pub unchanged: usize,
+}
+
+/// Which part of a Parquet file becomes a partition of the index.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    #[default]
+    File,
+    RowGroup,
+}
+
+/// The columns of an index built from Parquet files and the types of their values,
+/// stored next to the index so values can later be hashed the same way for queries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IndexedColumns {
+    pub columns: BTreeMap<String, ValueType>,
+    /// How the files were split into partitions, so updates index new files the same
+    /// way. Files written before it was recorded were indexed per file.
+    #[serde(default)]
+    pub granularity: Granularity,
+}
+
+impl IndexedColumns {
+    const FILE_NAME: &'static str = "columns.json";
+
+    pub fn load(storage_root: &Path) -> anyhow::Result<Self> {
+        let content = fs::read(storage_root.join(Self::FILE_NAME))?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    pub fn store(&self, storage_root: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(storage_root)?;
+        fs::write(
+            storage_root.join(Self::FILE_NAME),
+            serde_json::to_vec_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    /// Record the types of the given columns of a Parquet file, failing if a column
+    /// has a different type than in the files added before.
+    pub fn add_file(&mut self, path: &Path, columns: &[&str]) -> anyhow::Result<()> {
+        let metadata = read::read_metadata(&mut File::open(path)?)?;
+        let schema = read::infer_schema(&metadata)?;
+        for column in columns {
+            let field = schema
+                .fields
+                .iter()
+                .find(|field| &field.name == column)
+                .ok_or_else(|| anyhow::anyhow!("column '{}' not found in {:?}", column, path))?;
+            let value_type = value_type(field.data_type()).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "column '{}' has unsupported type {:?}",
+                    column,
+                    field.data_type()
+                )
+            })?;
+            let known = self.columns.entry(column.to_string()).or_insert(value_type);
+            anyhow::ensure!(
+                *known == value_type,
+                "column '{}' is {} in {:?}, but {} in other files",
+                column,
+                value_type,
+                path,
+                known
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The type the values of a column are hashed as, `None` if they can't be indexed.
+pub fn value_type(data_type: &DataType) -> Option<ValueType> {
+    Some(match data_type.to_logical_type() {
+        DataType::Boolean => ValueType::Bool,
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64 => ValueType::Int,
+        DataType::Float32 | DataType::Float64 => ValueType::Float,
+        DataType::Date32 => ValueType::Date,
+        DataType::Decimal(_, _) => ValueType::Decimal,
+        DataType::Utf8 | DataType::LargeUtf8 => ValueType::String,
+        DataType::Binary | DataType::LargeBinary => ValueType::Binary,
+        _ => return None,
+    })
+}
+
+/// Index the given columns of a Parquet file, or of all `.parquet` files in a directory
+/// and its subdirectories, adding one partition per file or row group.
+///
+/// Every non-null value is hashed by its type, see [`Hashable`], and mixed with the
+/// name of its column, see [`column_key`], before the values of all columns are added
+/// to the same partition: query the key of a value in a column to find the partitions
+/// that may contain it in that column. Returns the number of partitions added.
+///
+/// The partitions are only added to the in-memory part of the index, call
+/// [`PersistentIndex::persist`] to write them to disk.
+pub fn index_parquet(
+    index: &mut PersistentIndex<ParquetPartition>,
+    path: &Path,
+    columns: &[&str],
+    granularity: Granularity,
+) -> anyhow::Result<usize> {
+    let mut added = 0;
+    for file in find_parquet_files(path)? {
+        added += index_parquet_file(index, &file, columns, granularity)?;
+    }
+    Ok(added)
+}
+
+/// The Parquet file at `path`, or all `.parquet` files below it in a stable order.
+pub fn find_parquet_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = vec![];
+    let mut entries: Vec<_> = path.read_dir()?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            files.append(&mut find_parquet_files(&path)?);
+        } else if path.extension().is_some_and(|ext| ext == "parquet") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Query the partitions that may contain `value` in `column`, first pruning them by
+/// the min/max statistics of the column, which is exact, then checking the fingerprints
+/// of the remaining ones, which may yield false positives. The index isn't read at all
+/// if the statistics rule out every partition.
+pub fn query_parquet(
+    index: &PersistentIndex<ParquetPartition>,
+    column: &str,
+    value: &Value,
+) -> anyhow::Result<PrunedQuery> {
+    let mut total = 0;
+    let mut remaining = HashSet::new();
+    for (id, partition) in index.active_partitions_by_id() {
+        total += 1;
+        if partition.may_contain(column, value) {
+            remaining.insert(id);
+        }
+    }
+    let partitions: Vec<_> = if remaining.is_empty() {
+        vec![]
+    } else {
+        index
+            .query_ids(column_key(column, value.index_key()))?
+            .into_iter()
+            .filter(|id| remaining.contains(id))
+            .map(|id| {
+                index
+                    .partition(id)
+                    .expect("query yielded unknown partition")
+                    .clone()
+            })
+            .collect()
+    };
+    Ok(PrunedQuery {
+        total,
+        pruned_by_statistics: total - remaining.len(),
+        pruned_by_index: remaining.len() - partitions.len(),
+        partitions,
+    })
+}
+
+/// Bring an index built by [`index_parquet`] up to date with the Parquet files below
+/// `path`: files that are new or whose [`FileVersion`] changed are indexed, and the
+/// partitions of files that were deleted or changed are removed.
+///
+/// Files are identified by their path, so `path` must be given the same way as when
+/// building the index. Partitions of files outside of `path` are left untouched.
+///
+/// The columns and granularity are taken from `indexed_columns`, which records the
+/// types of the new files. Their columns are checked before the index is touched, so
+/// a file with a missing column or a conflicting type leaves the index unchanged.
+///
+/// Both the new partitions and the removals only change the in-memory part of the
+/// index; call [`PersistentIndex::persist`] to commit them together, so files are never
+/// left without partitions if that fails, and [`PersistentIndex::compact`] to reclaim
+/// the space of removed partitions.
+pub fn update_parquet(
+    index: &mut PersistentIndex<ParquetPartition>,
+    path: &Path,
+    indexed_columns: &mut IndexedColumns,
+) -> anyhow::Result<UpdateSummary> {
+    let columns: Vec<&str> = indexed_columns.columns.keys().map(String::as_str).collect();
+    let files = find_parquet_files(path)?
+        .into_iter()
+        .map(|file| Ok((FileVersion::of(&file)?, file)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let current: HashSet<_> = files
+        .iter()
+        .map(|(version, file)| (file, version))
+        .collect();
+
+    let mut indexed = HashMap::new();
+    let mut outdated = vec![];
+    for partition in index.active_partitions() {
+        if !partition.path.starts_with(path) {
+            continue;
+        }
+        indexed.insert(partition.path.clone(), partition.version);
+        if !current.contains(&(&partition.path, &partition.version)) {
+            outdated.push(partition.clone());
+        }
+    }
+
+    let mut summary = UpdateSummary::default();
+    for (version, file) in &files {
+        if indexed.get(file) == Some(version) {
+            summary.unchanged += 1;
+        } else {
+            summary.added.push(file.clone());
+        }
+    }
+    let mut checked = indexed_columns.clone();
+    for file in &summary.added {
+        checked.add_file(file, &columns)?;
+    }
+
+    index.remove_on_persist(&outdated)?;
+    summary.removed = outdated.iter().map(|p| p.path.clone()).collect();
+    summary.removed.sort();
+    summary.removed.dedup();
+
+    for file in &summary.added {
+        index_parquet_file(index, file, &columns, checked.granularity)?;
+    }
+    *indexed_columns = checked;
+    Ok(summary)
+}
+
+/// Index the given columns of a single Parquet file like [`index_parquet`].
+pub fn index_parquet_file(
+    index: &mut PersistentIndex<ParquetPartition>,
+    path: &Path,
+    columns: &[&str],
+    granularity: Granularity,
+) -> anyhow::Result<usize> {
+    let mut reader = File::open(path)?;
+    let metadata = read::read_metadata(&mut reader)?;
+    let schema = read::infer_schema(&metadata)?;
+    for column in columns {
+        anyhow::ensure!(
+            schema.fields.iter().any(|field| &field.name == column),
+            "column '{}' not found in {:?}",
+            column,
+            path
+        );
+    }
+    let schema = schema.filter(|_, field| columns.contains(&field.name.as_str()));
+
+    let row_groups: Vec<_> = match granularity {
+        Granularity::File => vec![(None, metadata.row_groups)],
+        Granularity::RowGroup => metadata
+            .row_groups
+            .into_iter()
+            .enumerate()
+            .map(|(idx, row_group)| (Some(idx), vec![row_group]))
+            .collect(),
+    };
+    let added = row_groups.len();
+    let version = FileVersion::of(path)?;
+    for (row_group, metadata) in row_groups {
+        let statistics = schema
+            .fields
+            .iter()
+            .map(|field| {
+                Ok((
+                    field.name.clone(),
+                    ColumnStatistics::read(field, &metadata)?,
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let reader = read::FileReader::new(
+            reader.try_clone()?,
+            metadata,
+            schema.clone(),
+            None,
+            None,
+            None,
+        );
+        let keys = hash_chunks(reader, &schema)?;
+        index.add(
+            keys.into_iter(),
+            ParquetPartition {
+                path: path.to_path_buf(),
+                row_group,
+                version,
+                statistics,
+            },
+        );
+    }
+    Ok(added)
+}
+
+/// A reader of the Parquet file at `path` that only reads the row groups which may
+/// contain rows matching `predicate`, according to `index`, and only the given
+/// `columns`, all of them if `None`. The values of `predicate` are keyed by their
+/// column like [`index_parquet`] keys them, see [`Predicate::evaluate_keyed`].
+///
+/// Files that aren't indexed, or were changed since they were indexed, can't be
+/// pruned and are read completely.
+pub fn read_pruned(
+    index: &PersistentIndex<ParquetPartition>,
+    predicate: &Predicate,
+    path: &Path,
+    columns: Option<&[&str]>,
+) -> anyhow::Result<read::FileReader<File>> {
+    let version = FileVersion::of(path)?;
+    let indexed = index
+        .active_partitions()
+        .any(|p| p.path == path && p.version == version);
+    let mut read_all = !indexed;
+    let mut candidates = HashSet::new();
+    if indexed {
+        for id in predicate.evaluate_keyed(index)?.partitions {
+            let partition = index
+                .partition(id)
+                .expect("query yielded unknown partition");
+            if partition.path == path && partition.version == version {
+                match partition.row_group {
+                    Some(row_group) => {
+                        candidates.insert(row_group);
+                    }
+                    None => read_all = true,
+                }
+            }
+        }
+    }
+
+    let mut reader = File::open(path)?;
+    let metadata = read::read_metadata(&mut reader)?;
+    let schema = read::infer_schema(&metadata)?;
+    let schema = match columns {
+        Some(columns) => schema.filter(|_, field| columns.contains(&field.name.as_str())),
+        None => schema,
+    };
+    let row_groups = metadata
+        .row_groups
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| read_all || candidates.contains(idx))
+        .map(|(_, row_group)| row_group)
+        .collect();
+    Ok(read::FileReader::new(
+        reader, row_groups, schema, None, None, None,
+    ))
+}
+
+fn hash_chunks(reader: read::FileReader<File>, schema: &Schema) -> anyhow::Result<Vec<u64>> {
+    let mut keys = vec![];
+    for chunk in reader {
+        for (array, field) in chunk?.columns().iter().zip(&schema.fields) {
+            let start = keys.len();
+            hash_array(array.as_ref(), &mut keys)
+                .map_err(|err| err.context(format!("column '{}'", field.name)))?;
+            for key in &mut keys[start..] {
+                *key = column_key(&field.name, *key);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Push the keys of all non-null values of `array`.
+fn hash_array(array: &dyn Array, keys: &mut Vec<u64>) -> anyhow::Result<()> {
+    fn hash_primitive<T: NativeType + Hashable>(array: &dyn Array, keys: &mut Vec<u64>) {
+        let array = array.as_any().downcast_ref::<PrimitiveArray<T>>().unwrap();
+        keys.extend(array.iter().flatten().map(|value| value.index_key()));
+    }
+
+    match array.data_type().to_logical_type() {
+        DataType::Boolean => {
+            let array = array.as_any().downcast_ref::<BooleanArray>().unwrap();
+            keys.extend(array.iter().flatten().map(|value| value.index_key()));
+        }
+        DataType::Int8 => hash_primitive::<i8>(array, keys),
+        DataType::Int16 => hash_primitive::<i16>(array, keys),
+        DataType::Int32 => hash_primitive::<i32>(array, keys),
+        DataType::Int64 => hash_primitive::<i64>(array, keys),
+        DataType::UInt8 => hash_primitive::<u8>(array, keys),
+        DataType::UInt16 => hash_primitive::<u16>(array, keys),
+        DataType::UInt32 => hash_primitive::<u32>(array, keys),
+        DataType::UInt64 => hash_primitive::<u64>(array, keys),
+        DataType::Float32 => hash_primitive::<f32>(array, keys),
+        DataType::Float64 => hash_primitive::<f64>(array, keys),
+        DataType::Date32 => {
+            let array = array
+                .as_any()
+                .downcast_ref::<PrimitiveArray<i32>>()
+                .unwrap();
+            keys.extend(array.iter().flatten().map(|days| Date(*days).index_key()));
+        }
+        DataType::Decimal(_, scale) => {
+            let scale = u8::try_from(*scale)?;
+            let array = array
+                .as_any()
+                .downcast_ref::<PrimitiveArray<i128>>()
+                .unwrap();
+            keys.extend(array.iter().flatten().map(|value| {
+                Decimal {
+                    value: *value,
+                    scale,
+                }
+                .index_key()
+            }));
+        }
+        DataType::Utf8 => {
+            let array = array.as_any().downcast_ref::<Utf8Array<i32>>().unwrap();
+            keys.extend(array.iter().flatten().map(|value| value.index_key()));
+        }
+        DataType::LargeUtf8 => {
+            let array = array.as_any().downcast_ref::<Utf8Array<i64>>().unwrap();
+            keys.extend(array.iter().flatten().map(|value| value.index_key()));
+        }
+        DataType::Binary => {
+            let array = array.as_any().downcast_ref::<BinaryArray<i32>>().unwrap();
+            keys.extend(array.iter().flatten().map(|value| value.index_key()));
+        }
+        DataType::LargeBinary => {
+            let array = array.as_any().downcast_ref::<BinaryArray<i64>>().unwrap();
+            keys.extend(array.iter().flatten().map(|value| value.index_key()));
+        }
+        other => anyhow::bail!("unsupported data type {:?}", other),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{fs::File, path::Path};
+
+    use arrow2::{
+        array::{Int64Array, Utf8Array},
+        chunk::Chunk,
+        datatypes::{Field, Schema},
+        io::parquet::write::{
+            transverse, CompressionOptions, Encoding, FileWriter, RowGroupIterator, Version,
+            WriteOptions,
+        },
+    };
+
+    use super::{
+        index_parquet, query_parquet, read_pruned, update_parquet, ColumnStatistics, FileVersion,
+        Granularity, IndexedColumns, ParquetPartition,
+    };
+    use crate::index::key::{column_key, Hashable, Value, ValueType};
+    use crate::index::predicate::Predicate;
+    use crate::index::{
+        poc::{LoadOptions, PersistentIndex},
+        PartitionFilter,
+    };
+
+    /// Write a file with the columns `id` (Int64) and `name` (Utf8), with consecutive ids
+    /// starting at `first_id` and one row group per entry of `row_groups`, holding that
+    /// many rows. Names are `name-{id}`, every third name is null.
+    pub(crate) fn write_parquet(
+        path: &Path,
+        first_id: i64,
+        row_groups: &[i64],
+    ) -> anyhow::Result<()> {
+        let schema = Schema::from(vec![
+            Field::new("id", arrow2::datatypes::DataType::Int64, false),
+            Field::new("name", arrow2::datatypes::DataType::Utf8, true),
+        ]);
+        let mut start = first_id;
+        let ids: Vec<_> = row_groups
+            .iter()
+            .map(|rows| {
+                let ids = start..start + rows;
+                start += rows;
+                ids
+            })
+            .collect();
+        let chunks = ids.into_iter().map(|ids| {
+            let names: Utf8Array<i32> = ids
+                .clone()
+                .map(|id| (id % 3 != 0).then(|| format!("name-{}", id)))
+                .collect();
+            let ids = Int64Array::from_vec(ids.clone().collect());
+            Ok(Chunk::new(vec![ids.boxed(), names.boxed()]))
+        });
+        let options = WriteOptions {
+            write_statistics: true,
+            compression: CompressionOptions::Uncompressed,
+            version: Version::V2,
+            data_pagesize_limit: None,
+        };
+        let encodings = schema
+            .fields
+            .iter()
+            .map(|f| transverse(&f.data_type, |_| Encoding::Plain))
+            .collect();
+        let row_groups = RowGroupIterator::try_new(chunks, &schema, options, encodings)?;
+        let mut writer = FileWriter::try_new(File::create(path)?, schema, options)?;
+        for group in row_groups {
+            writer.write(group?)?;
+        }
+        writer.end(None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn index_row_groups() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("data.parquet");
+        write_parquet(&path, 0, &[100, 100])?;
+        let mut index = PersistentIndex::try_new(
+            16,
+            temp_dir.path().join("index").to_str().unwrap().to_string(),
+        )?;
+        let added = index_parquet(&mut index, &path, &["id", "name"], Granularity::RowGroup)?;
+        assert_eq!(added, 2);
+        index.persist()?;
+
+        let row_groups = |key: u64| -> anyhow::Result<Vec<_>> {
+            Ok(index.query(key)?.into_iter().map(|p| p.row_group).collect())
+        };
+        let id = |value: i64| column_key("id", value.index_key());
+        let name = |value: &str| column_key("name", value.index_key());
+        assert!(row_groups(id(42))?.contains(&Some(0)));
+        // the key doesn't depend on the integer width
+        assert!(row_groups(column_key("id", 142i32.index_key()))?.contains(&Some(1)));
+        assert!(row_groups(name("name-143"))?.contains(&Some(1)));
+        assert!(!row_groups(name("name-143"))?.contains(&Some(0)));
+        // values are only found in their own column
+        assert!(row_groups(42i64.index_key())?.is_empty());
+        assert!(row_groups(column_key("name", 42i64.index_key()))?.is_empty());
+
+        let partitions: Vec<_> = index.partitions().collect();
+        assert_eq!(partitions[1].version, FileVersion::of(&path)?);
+        assert_eq!(
+            partitions[1].statistics["id"],
+            ColumnStatistics {
+                min: Some(Value::Int(100)),
+                max: Some(Value::Int(199)),
+                null_count: Some(0),
+            }
+        );
+        assert_eq!(
+            partitions[1].statistics["name"],
+            ColumnStatistics {
+                min: Some(Value::String("name-100".into())),
+                max: Some(Value::String("name-199".into())),
+                null_count: Some(33),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn index_directory_of_files() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let data_dir = temp_dir.path().join("data");
+        std::fs::create_dir_all(data_dir.join("nested"))?;
+        write_parquet(&data_dir.join("a.parquet"), 0, &[50, 50])?;
+        write_parquet(&data_dir.join("nested").join("b.parquet"), 100, &[100])?;
+        std::fs::write(data_dir.join("README"), "not a parquet file")?;
+        let mut index = PersistentIndex::try_new(
+            16,
+            temp_dir.path().join("index").to_str().unwrap().to_string(),
+        )?;
+        let added = index_parquet(&mut index, &data_dir, &["id"], Granularity::File)?;
+        assert_eq!(added, 2);
+        let files = |value: i64| -> anyhow::Result<Vec<_>> {
+            Ok(index
+                .query(column_key("id", value.index_key()))?
+                .into_iter()
+                .map(|p| (p.path, p.row_group))
+                .collect())
+        };
+        assert!(files(75)?.contains(&(data_dir.join("a.parquet"), None)));
+        assert!(files(150)?.contains(&(data_dir.join("nested").join("b.parquet"), None)));
+
+        // the statistics of a file combine those of its row groups
+        let partitions: Vec<_> = index.partitions().collect();
+        assert_eq!(partitions[0].statistics["id"].min, Some(Value::Int(0)));
+        assert_eq!(partitions[0].statistics["id"].max, Some(Value::Int(99)));
+        Ok(())
+    }
+
+    #[test]
+    fn prune_by_statistics_and_index() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("data.parquet");
+        write_parquet(&path, 0, &[100, 100])?;
+        let mut index = PersistentIndex::try_new(
+            16,
+            temp_dir.path().join("index").to_str().unwrap().to_string(),
+        )?;
+        index_parquet(&mut index, &path, &["id", "name"], Granularity::RowGroup)?;
+        index.persist()?;
+
+        let result = query_parquet(&index, "id", &Value::Int(142))?;
+        assert_eq!(result.partitions.len(), 1);
+        assert_eq!(result.partitions[0].row_group, Some(1));
+        assert_eq!(result.total, 2);
+        assert_eq!(result.pruned_by_statistics, 1);
+        assert_eq!(result.pruned_by_index, 0);
+
+        let result = query_parquet(&index, "id", &Value::Int(1000))?;
+        assert!(result.partitions.is_empty());
+        assert_eq!(result.pruned_by_statistics, 2);
+
+        // "name-142" lies between "name-1" and "name-98", the bounds of the first
+        // row group, which only the index can rule out
+        let result = query_parquet(&index, "name", &Value::String("name-142".into()))?;
+        assert_eq!(result.partitions.len(), 1);
+        assert_eq!(result.partitions[0].row_group, Some(1));
+        assert_eq!(result.pruned_by_statistics, 0);
+        assert_eq!(result.pruned_by_index, 1);
+
+        // values of other kinds can't be compared to the statistics
+        let result = query_parquet(&index, "id", &Value::String("name-142".into()))?;
+        assert_eq!(result.pruned_by_statistics, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn skip_index_if_statistics_prune_everything() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("data.parquet");
+        write_parquet(&path, 0, &[100, 100])?;
+        let root = temp_dir.path().join("index");
+        let mut index = PersistentIndex::try_new(16, root.to_str().unwrap().to_string())?;
+        index_parquet(&mut index, &path, &["id"], Granularity::RowGroup)?;
+        index.persist()?;
+        drop(index);
+
+        // damage every fingerprint, so any read of the index fails its checksum
+        for entry in std::fs::read_dir(root.join("segments"))? {
+            let segment = entry?.path();
+            let mut content = std::fs::read(&segment)?;
+            // header, offsets and checksums of the 16 buckets
+            let payload = 32 + 17 * 8 + 17 * 4;
+            content[payload..]
+                .iter_mut()
+                .for_each(|byte| *byte = !*byte);
+            std::fs::write(&segment, content)?;
+        }
+        let index = PersistentIndex::try_load_from_disk_with(
+            root.to_str().unwrap().to_string(),
+            LoadOptions {
+                verify_on_load: false,
+                verify_on_read: true,
+                ..Default::default()
+            },
+        )?;
+        assert!(query_parquet(&index, "id", &Value::Int(142)).is_err());
+        let result = query_parquet(&index, "id", &Value::Int(1000))?;
+        assert!(result.partitions.is_empty());
+        assert_eq!(result.pruned_by_statistics, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn update_changed_files() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let data_dir = temp_dir.path().join("data");
+        std::fs::create_dir_all(&data_dir)?;
+        let [a, b, c, d] =
+            ["a", "b", "c", "d"].map(|name| data_dir.join(name).with_extension("parquet"));
+        write_parquet(&a, 0, &[50, 50])?;
+        write_parquet(&b, 100, &[100])?;
+        write_parquet(&c, 200, &[100])?;
+        let mut index = PersistentIndex::try_new(
+            16,
+            temp_dir.path().join("index").to_str().unwrap().to_string(),
+        )?;
+        index_parquet(&mut index, &data_dir, &["id"], Granularity::RowGroup)?;
+        index.persist()?;
+        let mut columns = IndexedColumns {
+            granularity: Granularity::RowGroup,
+            ..Default::default()
+        };
+        columns.columns.insert("id".to_string(), ValueType::Int);
+
+        std::fs::remove_file(&b)?;
+        write_parquet(&c, 300, &[50])?;
+        write_parquet(&d, 400, &[50])?;
+        let summary = update_parquet(&mut index, &data_dir, &mut columns)?;
+        assert_eq!(summary.added, vec![c.clone(), d.clone()]);
+        assert_eq!(summary.removed, vec![b.clone(), c.clone()]);
+        assert_eq!(summary.unchanged, 1);
+        // nothing is committed before persisting, so a crash keeps the old partitions
+        let committed: PersistentIndex<ParquetPartition> = PersistentIndex::try_load_from_disk(
+            temp_dir.path().join("index").to_str().unwrap().to_string(),
+        )?;
+        assert!(committed.active_partitions().any(|p| p.path == b));
+        index.persist()?;
+
+        let paths = |value: i64| -> anyhow::Result<Vec<_>> {
+            Ok(index
+                .query(column_key("id", value.index_key()))?
+                .into_iter()
+                .map(|p| p.path)
+                .collect())
+        };
+        assert!(paths(75)?.contains(&a));
+        assert!(!paths(150)?.contains(&b));
+        assert!(!paths(250)?.contains(&c));
+        assert!(paths(325)?.contains(&c));
+        assert!(paths(425)?.contains(&d));
+
+        // nothing changed since the last update
+        let summary = update_parquet(&mut index, &data_dir, &mut columns)?;
+        assert!(summary.added.is_empty() && summary.removed.is_empty());
+        assert_eq!(summary.unchanged, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_update_before_changing_the_index() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let data_dir = temp_dir.path().join("data");
+        std::fs::create_dir_all(&data_dir)?;
+        let [a, b] = ["a", "b"].map(|name| data_dir.join(name).with_extension("parquet"));
+        write_parquet(&a, 0, &[50])?;
+        let mut index = PersistentIndex::try_new(
+            16,
+            temp_dir.path().join("index").to_str().unwrap().to_string(),
+        )?;
+        index_parquet(&mut index, &data_dir, &["id"], Granularity::File)?;
+        index.persist()?;
+        let mut columns = IndexedColumns::default();
+        columns.columns.insert("id".to_string(), ValueType::String);
+
+        // `a` is rewritten, but `b` has an `id` of another type than recorded
+        write_parquet(&a, 100, &[50])?;
+        write_parquet(&b, 200, &[50])?;
+        let before = columns.clone();
+        assert!(update_parquet(&mut index, &data_dir, &mut columns).is_err());
+        assert_eq!(columns, before);
+        assert_eq!(index.active_partitions().count(), 1);
+        assert!(index
+            .query(column_key("id", 25i64.index_key()))?
+            .into_iter()
+            .any(|p| p.path == a));
+        Ok(())
+    }
+
+    #[test]
+    fn read_candidate_row_groups() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("data.parquet");
+        let other = temp_dir.path().join("other.parquet");
+        write_parquet(&path, 0, &[50, 50, 50, 50])?;
+        write_parquet(&other, 0, &[50, 50, 50, 50])?;
+        let mut index = PersistentIndex::try_new(
+            16,
+            temp_dir.path().join("index").to_str().unwrap().to_string(),
+        )?;
+        index_parquet(&mut index, &path, &["id"], Granularity::RowGroup)?;
+        index.persist()?;
+
+        let ids = |predicate: &Predicate, path| -> anyhow::Result<Vec<i64>> {
+            let mut ids = vec![];
+            for chunk in read_pruned(&index, predicate, path, Some(&["id"]))? {
+                let chunk = chunk?;
+                let array = chunk.columns()[0]
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap();
+                ids.extend(array.values().iter());
+            }
+            Ok(ids)
+        };
+        let predicate = Predicate::Or(vec![
+            Predicate::Eq("id".to_string(), 25i64.index_key()),
+            Predicate::Eq("id".to_string(), 175i64.index_key()),
+        ]);
+        assert_eq!(
+            ids(&predicate, &path)?,
+            (0..50).chain(150..200).collect::<Vec<_>>()
+        );
+        assert!(ids(&Predicate::Eq("id".to_string(), 1000i64.index_key()), &path)?.is_empty());
+        // files that aren't indexed can't be pruned
+        assert_eq!(ids(&predicate, &other)?, (0..200).collect::<Vec<_>>());
+
+        // neither can files that changed since they were indexed
+        write_parquet(&path, 1000, &[50, 50])?;
+        assert_eq!(ids(&predicate, &path)?, (1000..1100).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unknown_columns() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("data.parquet");
+        write_parquet(&path, 0, &[10])?;
+        let mut index = PersistentIndex::try_new(
+            16,
+            temp_dir.path().join("index").to_str().unwrap().to_string(),
+        )?;
+        assert!(index_parquet(&mut index, &path, &["missing"], Granularity::File).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn record_column_types() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("data.parquet");
+        write_parquet(&path, 0, &[10])?;
+        let mut columns = IndexedColumns::default();
+        columns.add_file(&path, &["id", "name"])?;
+        assert_eq!(columns.columns["id"], ValueType::Int);
+        assert_eq!(columns.columns["name"], ValueType::String);
+        assert!(columns.add_file(&path, &["missing"]).is_err());
+
+        let root = temp_dir.path().join("index");
+        columns.granularity = Granularity::RowGroup;
+        columns.store(&root)?;
+        assert_eq!(IndexedColumns::load(&root)?, columns);
+
+        // Files written before the granularity was recorded are read as per-file.
+        std::fs::write(root.join("columns.json"), r#"{"columns": {"id": "int"}}"#)?;
+        assert_eq!(IndexedColumns::load(&root)?.granularity, Granularity::File);
+
+        columns.columns.insert("name".to_string(), ValueType::Int);
+        assert!(columns.add_file(&path, &["name"]).is_err());
+        Ok(())
+    }
+}